@@ -3,165 +3,580 @@ use crate::lexer;
 use crate::Args;
 
 use std::process;
+use std::cmp::Ordering;
 use std::collections::HashMap;
 
+pub struct Memory {
+    cells: Vec<u64>,
+    map: HashMap<u64, usize>,
+    offset: u64,
+    allocations: HashMap<u64, (u64, usize)>,
+    free_blocks: HashMap<u64, Vec<u64>>,
+}
+
+impl Memory {
+    pub fn new() -> Memory {
+        Memory {
+            cells: Vec::new(),
+            map: HashMap::new(),
+            offset: 0,
+            allocations: HashMap::new(),
+            free_blocks: HashMap::new(),
+        }
+    }
+
+    /// Reserves `size` zeroed cells that are never freed, like the data segment
+    pub fn reserve(&mut self, size: u64) -> u64 {
+        let base_addr = self.offset;
+        for _ in 0..size {
+            self.cells.push(0);
+            self.map.insert(self.offset, self.cells.len());
+            self.offset += 1;
+        }
+        base_addr
+    }
+
+    /// Hands out `size` zeroed cells, reusing a freed block of the same size when there is one,
+    /// and remembers the instruction that asked for them
+    pub fn allocate(&mut self, size: u64, ip: usize) -> u64 {
+        let base_addr = match self.free_blocks.get_mut(&size).and_then(|blocks| blocks.pop()) {
+            Some(base_addr) => {
+                for addr in base_addr..base_addr + size {
+                    self.store(addr, 0);
+                }
+                base_addr
+            },
+            None => self.reserve(size),
+        };
+        self.allocations.insert(base_addr, (size, ip));
+        base_addr
+    }
+
+    /// Gives the cells of an allocation back for later allocations of the same size
+    pub fn free(&mut self, addr: u64) -> bool {
+        match self.allocations.remove(&addr) {
+            Some((size, _)) => {
+                self.free_blocks.entry(size).or_default().push(addr);
+                true
+            },
+            None => false,
+        }
+    }
+
+    pub fn load(&self, addr: u64) -> u64 {
+        match self.map.get(&addr) {
+            Some(index) => self.cells[*index-1],
+            None => 0,
+        }
+    }
+
+    pub fn store(&mut self, addr: u64, value: u64) {
+        match self.map.get(&addr) {
+            Some(index) => {
+                self.cells[*index-1] = value;
+            },
+            None => {
+                self.cells.push(value);
+                self.map.insert(addr, self.cells.len());
+                self.offset += 1;
+            },
+        }
+    }
+
+    /// Reads the zero terminated string starting at `addr`
+    pub fn load_string(&self, mut addr: u64) -> String {
+        let mut bytes = Vec::new();
+        loop {
+            let byte = self.load(addr);
+            if byte == 0 {
+                return String::from_utf8_lossy(&bytes).into_owned();
+            }
+            bytes.push(byte as u8);
+            addr += 1;
+        }
+    }
+
+    pub fn report_leaks(&self, positions: &[lexer::Position]) {
+        let mut leaks = self.allocations.iter().collect::<Vec<_>>();
+        leaks.sort_by_key(|leak| leak.0);
+        println!("Leak check: {} allocation(s) not freed", leaks.len());
+        for (addr, (size, ip)) in leaks {
+            println!("    {} cell(s) at {} allocated at {}", size, addr, positions[*ip]);
+        }
+    }
+}
+
+pub struct Frame {
+    locals: Vec<(lexer::Types, u64)>,
+    buffer: u64,
+}
+
+impl Frame {
+    /// Allocates the local buffers of `procedure`, which are released again by `Frame::release`
+    pub fn enter(procedure: &lexer::Procedure, memory: &mut Memory, ip: usize) -> Frame {
+        let mut buffer = 0;
+        if procedure.FrameSize > 0 {
+            buffer = memory.allocate(procedure.FrameSize, ip);
+        }
+        Frame {
+            locals: Vec::new(),
+            buffer,
+        }
+    }
+
+    pub fn release(self, procedure: &lexer::Procedure, memory: &mut Memory) {
+        if procedure.FrameSize > 0 {
+            memory.free(self.buffer);
+        }
+    }
+}
+
+/// An active `try` block, holding what a `throw` inside it restores before jumping to `catch`
+struct Handler {
+    catch: usize,
+    calls: usize,
+    locals: usize,
+    stack: Vec<(lexer::Types, u64)>,
+}
+
+/// How many of the most recent calls a runtime error prints
+const TRACE_DEPTH: usize = 16;
+
 pub struct Interpreter;
 
 impl Interpreter {
-    pub fn expect(stack: &Vec<(lexer::Types, u64)>, expected_types: Vec<lexer::Types>) {
-        let mut ctr = 1;
-        for expected in expected_types {
+    /// Reports a runtime error at `ip` followed by the procedures that led there, most recent first
+    pub fn error(positions: &[lexer::Position], ip: usize, return_stack: &[(usize, String)], message: String) -> ! {
+        println!("{}: Runtime error: {}", positions[ip], message);
+        for (call, procedure) in return_stack.iter().rev().take(TRACE_DEPTH) {
+            println!("    in {} called from {}", procedure, positions[*call]);
+        }
+        if return_stack.len() > TRACE_DEPTH {
+            println!("    ... {} more", return_stack.len() - TRACE_DEPTH);
+        }
+        println!("    in main");
+        process::exit(1);
+    }
+
+    /// A call is in tail position when nothing but the end of let blocks is left before the
+    /// return of a procedure other than main, and it isn't inside a try block of that procedure.
+    /// Procedures with local buffers keep their frame, since the callee may have been passed
+    /// a pointer into it
+    fn is_tail_call(tokens: &[lexer::OpCodes], ip: usize, procedures: &HashMap<String, lexer::Procedure>, return_stack: &[(usize, String)], handlers: &[Handler]) -> bool {
+        let next = tokens[ip + 1..].iter().find(|token| !matches!(token, lexer::OpCodes::Unbind(_)));
+        matches!(next, Some(lexer::OpCodes::Return))
+            && return_stack.last().is_some_and(|(_, caller)| procedures.get(caller).unwrap().FrameSize == 0)
+            && handlers.last().is_none_or(|handler| handler.calls < return_stack.len())
+    }
+
+    /// Enters `procedure` called at `ip` and returns where to continue. A tail call releases
+    /// the frame of the caller first and returns to where the caller would have returned to
+    fn enter(
+        procedure: &lexer::Procedure,
+        procedures: &HashMap<String, lexer::Procedure>,
+        ip: usize,
+        tail: bool,
+        return_stack: &mut Vec<(usize, String)>,
+        frames: &mut Vec<Frame>,
+        memory: &mut Memory,
+    ) -> usize {
+        let mut call = ip;
+        if tail {
+            let (caller_call, caller) = return_stack.pop().unwrap();
+            frames.pop().unwrap().release(procedures.get(&caller).unwrap(), memory);
+            call = caller_call;
+        }
+        return_stack.push((call, procedure.Proc.clone()));
+        frames.push(Frame::enter(procedure, memory, ip));
+        procedure.Location
+    }
+
+    /// Applies `lhs rhs <operator>`, failing on overflow unless the operator wraps or saturates
+    pub fn arithmetic(operator: &lexer::Arithmetic, lhs: &(lexer::Types, u64), rhs: &(lexer::Types, u64)) -> Result<(lexer::Types, u64), String> {
+        let result_type = operator.result_type(&lhs.0, &rhs.0);
+        if result_type.is_none() {
+            return Err(format!("Can't apply {:?} to {:?} and {:?}", operator, lhs.0, rhs.0));
+        }
+        let result_type = result_type.unwrap();
+        if result_type == lexer::Types::Float {
+            let value1 = f64::from_bits(lhs.1);
+            let value2 = f64::from_bits(rhs.1);
+            let result = match operator {
+                lexer::Arithmetic::Plus => value1 + value2,
+                lexer::Arithmetic::Minus => value1 - value2,
+                lexer::Arithmetic::Mul => value1 * value2,
+                lexer::Arithmetic::Div => value1 / value2,
+                _ => value1 % value2,
+            };
+            return Ok((result_type, result.to_bits()));
+        }
+        let value1 = lhs.0.to_integer(lhs.1);
+        let value2 = rhs.0.to_integer(rhs.1);
+        if value2 == 0 && matches!(operator, lexer::Arithmetic::Div | lexer::Arithmetic::Mod) {
+            return Err(String::from("Division by zero"));
+        }
+        let result = match operator {
+            lexer::Arithmetic::Plus | lexer::Arithmetic::WrappingPlus | lexer::Arithmetic::SaturatingPlus => value1 + value2,
+            lexer::Arithmetic::Minus | lexer::Arithmetic::WrappingMinus | lexer::Arithmetic::SaturatingMinus => value1 - value2,
+            lexer::Arithmetic::WrappingMul => value1.wrapping_mul(value2),
+            lexer::Arithmetic::Mul | lexer::Arithmetic::SaturatingMul => value1.saturating_mul(value2),
+            lexer::Arithmetic::Div => value1 / value2,
+            lexer::Arithmetic::Mod => value1 % value2,
+        };
+        let (min, max) = result_type.bounds();
+        match operator {
+            lexer::Arithmetic::WrappingPlus | lexer::Arithmetic::WrappingMinus | lexer::Arithmetic::WrappingMul => {
+                Ok((result_type.clone(), result_type.wrap_integer(result)))
+            },
+            lexer::Arithmetic::SaturatingPlus | lexer::Arithmetic::SaturatingMinus | lexer::Arithmetic::SaturatingMul => {
+                Ok((result_type.clone(), result_type.wrap_integer(result.clamp(min, max))))
+            },
+            _ => {
+                if result < min || result > max {
+                    return Err(format!("Integer overflow, {} {:?} {} doesn't fit in {:?}", value1, operator, value2, result_type));
+                }
+                Ok((result_type.clone(), result_type.wrap_integer(result)))
+            },
+        }
+    }
+
+    /// Applies `lhs rhs <operator>` to the raw bits, `shr` shifts in zeroes and `sar` the sign bit
+    pub fn bitwise(operator: &lexer::Bitwise, lhs: &(lexer::Types, u64), rhs: &(lexer::Types, u64)) -> Result<(lexer::Types, u64), String> {
+        let result_type = operator.result_type(&lhs.0, &rhs.0);
+        if result_type.is_none() {
+            return Err(format!("Can't apply {:?} to {:?} and {:?}", operator, lhs.0, rhs.0));
+        }
+        let result_type = result_type.unwrap();
+        if matches!(operator, lexer::Bitwise::Shl | lexer::Bitwise::Shr | lexer::Bitwise::Sar) && !(0..64).contains(&rhs.0.to_integer(rhs.1)) {
+            return Err(format!("Can't shift by {} bits", rhs.0.to_integer(rhs.1)));
+        }
+        let result = match operator {
+            lexer::Bitwise::And => lhs.1 & rhs.1,
+            lexer::Bitwise::Or => lhs.1 | rhs.1,
+            lexer::Bitwise::Xor => lhs.1 ^ rhs.1,
+            lexer::Bitwise::Not => !rhs.1,
+            lexer::Bitwise::Shl => lhs.1 << rhs.1,
+            lexer::Bitwise::Shr => ((lhs.1 << (64 - lhs.0.bits())) >> (64 - lhs.0.bits())) >> rhs.1,
+            lexer::Bitwise::Sar => (lhs.0.to_integer(lhs.1) >> rhs.1) as u64,
+        };
+        Ok((result_type.clone(), result_type.wrap_integer(result_type.to_integer(result))))
+    }
+
+    pub fn compare(operator: &lexer::Equality, lhs: &(lexer::Types, u64), rhs: &(lexer::Types, u64)) -> Result<(lexer::Types, u64), String> {
+        if !operator.accepts(&lhs.0, &rhs.0) {
+            return Err(format!("Can't compare {:?} and {:?}", lhs.0, rhs.0));
+        }
+        let ordering = match lhs.0 {
+            lexer::Types::Float => f64::from_bits(lhs.1).partial_cmp(&f64::from_bits(rhs.1)),
+            _ => Some(lhs.0.to_integer(lhs.1).cmp(&rhs.0.to_integer(rhs.1))),
+        };
+        let result = match operator {
+            lexer::Equality::Equal => ordering == Some(Ordering::Equal),
+            lexer::Equality::NotEqual => ordering != Some(Ordering::Equal),
+            lexer::Equality::Bigger => ordering == Some(Ordering::Greater),
+            lexer::Equality::BiggerEqual => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            lexer::Equality::Smaller => ordering == Some(Ordering::Less),
+            lexer::Equality::SmallerEqual => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+        };
+        Ok((lexer::Types::Bool, result as u64))
+    }
+
+    /// Runs the body of a `const` while loading the program. Only the instructions that work on
+    /// values alone are allowed: pushes, arithmetic, bitwise and boolean operators, comparisons,
+    /// casts and stack shuffling. Exactly one value has to be left on the stack
+    pub fn evaluate(tokens: &[lexer::OpCodes]) -> Result<(lexer::Types, u64), String> {
+        let mut stack: Vec<(lexer::Types, u64)> = Vec::new();
+        for token in tokens {
+            let allowed = matches!(
+                token,
+                lexer::OpCodes::Push(..) | lexer::OpCodes::Arithmetic(_) | lexer::OpCodes::DivMod | lexer::OpCodes::Bitwise(_) |
+                lexer::OpCodes::Equality(_) | lexer::OpCodes::Logic(_) | lexer::OpCodes::Cast(_) |
+                lexer::OpCodes::Dup | lexer::OpCodes::Swap | lexer::OpCodes::Rot | lexer::OpCodes::Drop
+            );
+            if !allowed {
+                return Err(format!("{:?} can't be used in a constant", token));
+            }
+            Interpreter::expect(&stack, Interpreter::operands(token, &HashMap::new(), &[]))?;
+            match token {
+                lexer::OpCodes::Push(lexer::Types::String, _, _) => {
+                    return Err(String::from("Strings can't be constants"));
+                },
+                lexer::OpCodes::Push(push_type, push_int, _) => {
+                    stack.push((push_type.clone(), *push_int));
+                },
+                lexer::OpCodes::Arithmetic(operator) => {
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    stack.push(Interpreter::arithmetic(operator, &value2, &value1)?);
+                },
+                lexer::OpCodes::DivMod => {
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    stack.push(Interpreter::arithmetic(&lexer::Arithmetic::Div, &value2, &value1)?);
+                    stack.push(Interpreter::arithmetic(&lexer::Arithmetic::Mod, &value2, &value1)?);
+                },
+                lexer::OpCodes::Bitwise(lexer::Bitwise::Not) => {
+                    let value = stack.pop().unwrap();
+                    stack.push(Interpreter::bitwise(&lexer::Bitwise::Not, &value, &value)?);
+                },
+                lexer::OpCodes::Bitwise(operator) => {
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    stack.push(Interpreter::bitwise(operator, &value2, &value1)?);
+                },
+                lexer::OpCodes::Equality(operator) => {
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    stack.push(Interpreter::compare(operator, &value2, &value1)?);
+                },
+                lexer::OpCodes::Logic(lexer::Logic::Not) => {
+                    let value = stack.pop().unwrap();
+                    stack.push((lexer::Types::Bool, (value.1 == 0) as u64));
+                },
+                lexer::OpCodes::Logic(operator) => {
+                    let value1 = stack.pop().unwrap().1 != 0;
+                    let value2 = stack.pop().unwrap().1 != 0;
+                    let result = match operator {
+                        lexer::Logic::And => value2 && value1,
+                        _ => value2 || value1,
+                    };
+                    stack.push((lexer::Types::Bool, result as u64));
+                },
+                lexer::OpCodes::Cast(cast_type) => {
+                    let value = stack.pop().unwrap();
+                    stack.push(Interpreter::cast(value, cast_type));
+                },
+                lexer::OpCodes::Dup => {
+                    stack.push(stack.last().unwrap().clone());
+                },
+                lexer::OpCodes::Swap => {
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    stack.push(value1);
+                    stack.push(value2);
+                },
+                lexer::OpCodes::Rot => {
+                    let values = stack.split_off(stack.len() - 3);
+                    stack.extend(values.into_iter().rev());
+                },
+                lexer::OpCodes::Drop => {
+                    stack.pop();
+                },
+                _ => {
+                    return Err(format!("{:?} can't be used in a constant", token));
+                },
+            }
+        }
+        if stack.len() != 1 {
+            return Err(format!("A constant has to leave exactly one value on the stack, not {}", stack.len()));
+        }
+        Ok(stack.pop().unwrap())
+    }
+
+    /// Converts between floats and integers and truncates or extends between integer widths,
+    /// a cast to bool is true for every value that isn't zero and every other cast keeps the
+    /// bits and only relabels them
+    pub fn cast(value: (lexer::Types, u64), cast_type: &lexer::Types) -> (lexer::Types, u64) {
+        match (&value.0, cast_type) {
+            (lexer::Types::Float, lexer::Types::Float) => value,
+            (lexer::Types::Float, lexer::Types::Bool) => (lexer::Types::Bool, (f64::from_bits(value.1) != 0.0) as u64),
+            (_, lexer::Types::Bool) => (lexer::Types::Bool, (value.1 != 0) as u64),
+            (lexer::Types::Float, _) if cast_type.is_integer() => {
+                (cast_type.clone(), cast_type.wrap_integer(f64::from_bits(value.1) as i128))
+            },
+            (_, lexer::Types::Float) => (lexer::Types::Float, (value.0.to_integer(value.1) as f64).to_bits()),
+            _ if cast_type.is_integer() => (cast_type.clone(), cast_type.wrap_integer(value.0.to_integer(value.1))),
+            _ => (cast_type.clone(), value.1),
+        }
+    }
+
+    /// Converts an int on the stack into a file descriptor for a syscall
+    fn file_descriptor(value: u64) -> Result<i32, String> {
+        i32::try_from(value as i64).ok().filter(|fd| *fd >= 0).ok_or(format!("Invalid file descriptor: {}", value as i64))
+    }
+
+    /// The types an instruction expects on top of the stack, the first one being the top
+    fn operands(token: &lexer::OpCodes, procedures: &HashMap<String, lexer::Procedure>, return_stack: &[(usize, String)]) -> Vec<lexer::Types> {
+        match token {
+            lexer::OpCodes::Syscalls(lexer::Syscalls::Read) => vec![lexer::Types::Int, lexer::Types::Int],
+            lexer::OpCodes::Syscalls(lexer::Syscalls::Write) => vec![lexer::Types::Int, lexer::Types::Pointer, lexer::Types::Int],
+            lexer::OpCodes::Throw | lexer::OpCodes::Free => vec![lexer::Types::Pointer],
+            lexer::OpCodes::Alloc => vec![lexer::Types::Int],
+            lexer::OpCodes::Case(..) | lexer::OpCodes::NoMatch(_) | lexer::OpCodes::Drop | lexer::OpCodes::Dup |
+            lexer::OpCodes::Load | lexer::OpCodes::Cast(_) | lexer::OpCodes::Print | lexer::OpCodes::Call |
+            lexer::OpCodes::Bitwise(lexer::Bitwise::Not) => vec![lexer::Types::Unknown],
+            lexer::OpCodes::Swap | lexer::OpCodes::Store | lexer::OpCodes::Arithmetic(_) | lexer::OpCodes::DivMod |
+            lexer::OpCodes::Bitwise(_) | lexer::OpCodes::Equality(_) => vec![lexer::Types::Unknown; 2],
+            lexer::OpCodes::Rot => vec![lexer::Types::Unknown; 3],
+            lexer::OpCodes::Bind(count) => vec![lexer::Types::Unknown; *count],
+            lexer::OpCodes::FieldLoad(struct_type, _, _) => vec![struct_type.clone()],
+            lexer::OpCodes::FieldStore(struct_type, _, field_type) => vec![struct_type.clone(), field_type.clone()],
+            lexer::OpCodes::Logic(lexer::Logic::Not) | lexer::OpCodes::If => vec![lexer::Types::Bool],
+            lexer::OpCodes::Logic(_) => vec![lexer::Types::Bool, lexer::Types::Bool],
+            lexer::OpCodes::Ident(ident) => {
+                procedures.get(ident).map(|procedure| procedure.ParameterTypes.clone()).unwrap_or_default()
+            },
+            lexer::OpCodes::Return => {
+                return_stack.last().map(|(_, name)| procedures.get(name).unwrap().ReturnTypes.clone()).unwrap_or_default()
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Checks the types on top of the stack, the first one being the top
+    pub fn expect(stack: &[(lexer::Types, u64)], expected_types: Vec<lexer::Types>) -> Result<(), String> {
+        for (ctr, expected) in (1..).zip(expected_types) {
             let stack_len = stack.len();
-            if stack_len < 1 {
-                println!("Not enough elements on the stack");
-                process::exit(1);
+            if stack_len < ctr {
+                return Err(String::from("Not enough elements on the stack"));
             }
+            let value = &stack[stack_len-ctr];
             if expected == lexer::Types::Unknown {
                 continue;
             }
-            let value = &stack[stack_len-ctr];
+            // The stack effect of a quotation was checked where it was passed as a procedure
+            if matches!((&expected, &value.0), (lexer::Types::Procedure(..), lexer::Types::Quotation(_))) {
+                continue;
+            }
             if expected != value.0 {
-                println!("Expected {:?} but got {:?}", expected, value.0);
-                process::exit(1);
+                return Err(format!("Expected {:?} but got {:?}", expected, value.0));
             }
-            ctr += 1;
         }
+        Ok(())
     }
 
-    pub fn run(program: (Vec<lexer::OpCodes>,
-                         HashMap<usize, usize>,
-                         HashMap<String,
-                         lexer::Procedure>, HashMap<String, usize>,
-                        ),
-               args: Args,
-               ) {
-        let tokens = program.0;
-        let scopes = program.1;
-        let procedures = program.2;
-        let labels = program.3;
-        let mut ip = 0;
+    pub fn run(program: lexer::Program, args: Args) {
+        let tokens = program.tokens;
+        let positions = program.positions;
+        let scopes = program.scopes;
+        let procedures = program.procedures;
+        let globals = program.globals;
+        let enums = program.enums;
         let mut stack: Vec<(lexer::Types, u64)> = Vec::new();
-        let mut memory: Vec<u64> = Vec::new();
-        let mut memory_map: HashMap<u64, usize> = HashMap::new();
-        let mut memory_offset = 0;
+        let mut memory = Memory::new();
+        let data_segment = memory.reserve(program.data_size);
         let mut return_stack: Vec<(usize, String)> = Vec::new();
+        let mut handlers: Vec<Handler> = Vec::new();
+        // String literals are stored once, the first time they are pushed
+        let mut literals: HashMap<usize, u64> = HashMap::new();
+        let references: HashMap<u64, &String> = procedures.values().map(|procedure| (procedure.Location as u64, &procedure.Proc)).collect();
 
         let main = procedures.get("main");
         if main.is_none() {
             println!("No main procedure was provided");
             process::exit(1);
         }
-        ip = main.unwrap().Location;
+        let mut ip = main.unwrap().Location;
+        let mut frames: Vec<Frame> = vec![Frame::enter(main.unwrap(), &mut memory, ip)];
 
         while ip < tokens.len() {
             let token = &tokens[ip];
             if args.debug {
                 println!("Ip: {} Token: {:?}", ip, token);
             }
+            if stack.len() > args.max_stack {
+                Interpreter::error(&positions, ip, &return_stack, format!("Stack overflow, more than {} values on the stack", args.max_stack));
+            }
+            if let Err(message) = Interpreter::expect(&stack, Interpreter::operands(token, &procedures, &return_stack)) {
+                Interpreter::error(&positions, ip, &return_stack, message);
+            }
             match token {
                 lexer::OpCodes::Syscalls(syscall) => {
                     match syscall { // TODO: Implement Open syscall
-                        lexer::Syscalls::Open => {
-                            Interpreter::expect(
-                                &stack,
-                                vec![],
-                            );
-                        },
+                        lexer::Syscalls::Open => {},
                         lexer::Syscalls::Read => {
-                            Interpreter::expect(
-                                &stack,
-                                vec![lexer::Types::Int, lexer::Types::Int],
-                            );
                             let fd = stack.pop().unwrap();
                             let buffer_len = stack.pop().unwrap();
+                            let fd = Interpreter::file_descriptor(fd.1).unwrap_or_else(|message| Interpreter::error(&positions, ip, &return_stack, message));
                             if buffer_len.1 > 199 {
-                                println!("Currently the read implementation only supports 200 bytes of length");
-                                process::exit(1);
+                                Interpreter::error(&positions, ip, &return_stack, String::from("Currently the read implementation only supports 200 bytes of length"));
                             }
                             let mut buffer: [u8; 200] = [0; 200];
 
                             unsafe {
-                                libc::read(fd.1 as i32, buffer.as_mut_ptr() as *mut libc::c_void, buffer_len.1 as usize);
+                                libc::read(fd, buffer.as_mut_ptr() as *mut libc::c_void, buffer_len.1 as usize);
                             }
-                            let base_addr = memory_offset;
-                            for byte in buffer {
-                                memory.push(byte as u64);
-                                memory_map.insert(memory_offset, memory.len());
-                                memory_offset += 1;
+                            let base_addr = memory.allocate(buffer.len() as u64 + 1, ip);
+                            for (offset, byte) in buffer.iter().enumerate() {
+                                memory.store(base_addr + offset as u64, *byte as u64);
                             }
-                            memory.push(0);
-                            memory_map.insert(memory_offset, memory.len());
-                            memory_offset += 1;
                             stack.push((lexer::Types::Pointer, base_addr));
                         },
                         lexer::Syscalls::Write => {
-                            Interpreter::expect(
-                                &stack,
-                                vec![lexer::Types::Int, lexer::Types::Pointer, lexer::Types::Int],
-                            );
                             let fd = stack.pop().unwrap();
                             let buffer_ptr = stack.pop().unwrap();
                             let buffer_len = stack.pop().unwrap();
-                            let mut buffer = String::new();
-                            let mut offset = 0;
-                            while buffer.len() < buffer_len.1 as usize {
-                                let addr = memory_map.get(&(buffer_ptr.1 + offset));
-                                if addr.is_none() {
-                                    buffer = buffer + &String::from_utf8(vec![0]).unwrap();
-                                } else {
-                                    buffer = buffer + &String::from_utf8(vec![memory[*addr.unwrap()-1] as u8]).unwrap();
-                                }
-                                offset += 1;
+                            let fd = Interpreter::file_descriptor(fd.1).unwrap_or_else(|message| Interpreter::error(&positions, ip, &return_stack, message));
+                            if (buffer_len.1 as i64) < 0 {
+                                Interpreter::error(&positions, ip, &return_stack, format!("Can't write {} bytes", buffer_len.1 as i64));
                             }
+                            let buffer = (0..buffer_len.1).map(|offset| memory.load(buffer_ptr.1 + offset) as u8).collect::<Vec<u8>>();
                             unsafe {
-                                let status = libc::write(fd.1.try_into().unwrap(), buffer.as_ptr() as *const libc::c_void, buffer_len.1.try_into().unwrap());
+                                let status = libc::write(fd, buffer.as_ptr() as *const libc::c_void, buffer_len.1 as usize);
                                 if status == -1 {
-                                    println!("Write error");
-                                    process::exit(1);
+                                    Interpreter::error(&positions, ip, &return_stack, String::from("Write error"));
                                 }
                             }
                         },
                     }
                 },
-                lexer::OpCodes::Goto(label) => {
-                    let _label = labels.get(label);
-                    if _label.is_none() {
-                        println!("Unknown label: {}", label);
-                        process::exit(1);
+                lexer::OpCodes::Goto(target) => {
+                    ip = *target;
+                    continue;
+                },
+                lexer::OpCodes::Try(catch) => {
+                    handlers.push(Handler {
+                        catch: *catch,
+                        calls: return_stack.len(),
+                        locals: frames.last().unwrap().locals.len(),
+                        stack: stack.clone(),
+                    });
+                },
+                lexer::OpCodes::EndTry => {
+                    handlers.pop().unwrap();
+                },
+                lexer::OpCodes::Throw => {
+                    let error = stack.pop().unwrap();
+                    let handler = handlers.pop();
+                    if handler.is_none() {
+                        Interpreter::error(&positions, ip, &return_stack, format!("Uncaught exception: {}", memory.load_string(error.1)));
+                    }
+                    let handler = handler.unwrap();
+                    while return_stack.len() > handler.calls {
+                        let (_, name) = return_stack.pop().unwrap();
+                        frames.pop().unwrap().release(procedures.get(&name).unwrap(), &mut memory);
+                    }
+                    frames.last_mut().unwrap().locals.truncate(handler.locals);
+                    stack = handler.stack;
+                    stack.push(error);
+                    ip = handler.catch;
+                    continue;
+                },
+                lexer::OpCodes::Case(case_type, value, next) => {
+                    if stack.last().unwrap() != &(case_type.clone(), *value) {
+                        ip = *next;
+                        continue;
                     }
-                    ip = *_label.unwrap();
+                    stack.pop().unwrap();
+                },
+                lexer::OpCodes::NoMatch(_) => {
+                    stack.pop().unwrap();
                 },
                 lexer::OpCodes::Drop => {
-                    Interpreter::expect(
-                        &stack,
-                        vec![lexer::Types::Unknown],
-                    );
                     stack.pop().unwrap();
                 },
                 lexer::OpCodes::Dup => {
-                    Interpreter::expect(
-                        &stack,
-                        vec![lexer::Types::Unknown],
-                    );
                     let value = stack.pop().unwrap();
                     stack.push(value.clone());
                     stack.push(value);
                 },
                 lexer::OpCodes::Swap => {
-                    Interpreter::expect(
-                        &stack,
-                        vec![lexer::Types::Unknown, lexer::Types::Unknown],
-                    );
                     let value1 = stack.pop().unwrap();
                     let value2 = stack.pop().unwrap();
                     stack.push(value1);
                     stack.push(value2);
                 },
                 lexer::OpCodes::Rot => {
-                    Interpreter::expect(
-                        &stack,
-                        vec![lexer::Types::Unknown, lexer::Types::Unknown, lexer::Types::Unknown],
-                    );
                     let value1 = stack.pop().unwrap();
                     let value2 = stack.pop().unwrap();
                     let value3 = stack.pop().unwrap();
@@ -170,134 +585,168 @@ impl Interpreter {
                     stack.push(value3);
                 },
                 lexer::OpCodes::Ident(ident) => {
-                    if procedures.get(ident).is_some() {
-                        let procedure = procedures.get(ident).unwrap();
-                        return_stack.push((ip, procedure.Proc.clone()));
-                        Interpreter::expect(
-                            &stack,
-                            procedure.ParameterTypes.clone(),
-                        );
-                        ip = procedure.Location;
+                    if let Some(procedure) = procedures.get(ident) {
+                        let tail = Interpreter::is_tail_call(&tokens, ip, &procedures, &return_stack, &handlers);
+                        if !tail && return_stack.len() >= args.max_calls {
+                            Interpreter::error(&positions, ip, &return_stack, format!("Stack overflow, more than {} nested calls", args.max_calls));
+                        }
+                        ip = Interpreter::enter(procedure, &procedures, ip, tail, &mut return_stack, &mut frames, &mut memory);
                         continue;
+                    } else if let Some((offset, _)) = globals.get(ident) {
+                        stack.push((lexer::Types::Pointer, data_segment + offset));
                     } else {
-                        println!("Unknown ident: {}", ident);
-                        process::exit(1);
+                        Interpreter::error(&positions, ip, &return_stack, format!("Unknown ident: {}", ident));
                     }
                 },
-                lexer::OpCodes::Return => {
-                    let return_location = return_stack.pop();
-                    if return_location.is_none() {
-                        ip = tokens.len()-1;
+                lexer::OpCodes::Reference(name) => {
+                    let procedure = procedures.get(name);
+                    if procedure.is_none() {
+                        Interpreter::error(&positions, ip, &return_stack, format!("Unknown procedure: {}", name));
+                    }
+                    let procedure = procedure.unwrap();
+                    if procedure.is_quotation() {
+                        stack.push((lexer::Types::Quotation(name.clone()), procedure.Location as u64));
                     } else {
-                        let procedure = procedures.get(&return_location.as_ref().unwrap().1).unwrap();
-                        Interpreter::expect(
-                            &stack,
-                            procedure.ReturnTypes.clone(),
-                        );
-                        ip = return_location.unwrap().0;
+                        stack.push((
+                            lexer::Types::Procedure(procedure.ParameterTypes.clone(), procedure.ReturnTypes.clone()),
+                            procedure.Location as u64,
+                        ));
                     }
                 },
-                lexer::OpCodes::Load => {
-                    let addr = stack.pop();
-                    if addr.is_none() {
-                        println!("Stack underflow");
-                        process::exit(1);
+                lexer::OpCodes::Call => {
+                    let reference = stack.pop().unwrap();
+                    let procedure = references.get(&reference.1).filter(|_| matches!(reference.0, lexer::Types::Procedure(..) | lexer::Types::Quotation(_)));
+                    if procedure.is_none() {
+                        Interpreter::error(&positions, ip, &return_stack, format!("Can't call {:?}", reference.0));
+                    }
+                    let procedure = procedures.get(*procedure.unwrap()).unwrap();
+                    if let Err(message) = Interpreter::expect(&stack, procedure.ParameterTypes.clone()) {
+                        Interpreter::error(&positions, ip, &return_stack, message);
+                    }
+                    let tail = Interpreter::is_tail_call(&tokens, ip, &procedures, &return_stack, &handlers);
+                    if !tail && return_stack.len() >= args.max_calls {
+                        Interpreter::error(&positions, ip, &return_stack, format!("Stack overflow, more than {} nested calls", args.max_calls));
                     }
-                    let value = memory_map.get(&addr.unwrap().1);
-                    if value.is_none() {
-                        stack.push((lexer::Types::Int, 0));
+                    ip = Interpreter::enter(procedure, &procedures, ip, tail, &mut return_stack, &mut frames, &mut memory);
+                    continue;
+                },
+                lexer::OpCodes::Return => {
+                    if let Some(return_location) = return_stack.pop() {
+                        let procedure = procedures.get(&return_location.1).unwrap();
+                        frames.pop().unwrap().release(procedure, &mut memory);
+                        handlers.retain(|handler| handler.calls <= return_stack.len());
+                        ip = return_location.0;
                     } else {
-                        stack.push((lexer::Types::Int, memory[*value.unwrap()-1]));
+                        frames.pop().unwrap().release(procedures.get("main").unwrap(), &mut memory);
+                        ip = tokens.len()-1;
                     }
                 },
+                lexer::OpCodes::Bind(count) => {
+                    let values = stack.split_off(stack.len() - count);
+                    frames.last_mut().unwrap().locals.extend(values);
+                },
+                lexer::OpCodes::Unbind(count) => {
+                    let locals = &mut frames.last_mut().unwrap().locals;
+                    locals.truncate(locals.len() - count);
+                },
+                lexer::OpCodes::Local(index) => {
+                    stack.push(frames.last().unwrap().locals[*index].clone());
+                },
+                lexer::OpCodes::Buffer(offset) => {
+                    stack.push((lexer::Types::Pointer, frames.last().unwrap().buffer + offset));
+                },
+                lexer::OpCodes::Load => {
+                    let addr = stack.pop().unwrap();
+                    stack.push((lexer::Types::Int, memory.load(addr.1)));
+                },
                 lexer::OpCodes::Store => {
-                    let addr = stack.pop();
-                    let value = stack.pop();
-                    if addr.is_none() | value.is_none() {
-                        println!("Stack underflow");
-                        process::exit(1);
+                    let addr = stack.pop().unwrap();
+                    let value = stack.pop().unwrap();
+                    memory.store(addr.1, value.1);
+                },
+                lexer::OpCodes::FieldLoad(_, offset, field_type) => {
+                    let addr = stack.pop().unwrap();
+                    stack.push((field_type.clone(), memory.load(addr.1 + offset)));
+                },
+                lexer::OpCodes::FieldStore(_, offset, _) => {
+                    let addr = stack.pop().unwrap();
+                    let value = stack.pop().unwrap();
+                    memory.store(addr.1 + offset, value.1);
+                },
+                lexer::OpCodes::Alloc => {
+                    let size = stack.pop().unwrap();
+                    if size.1 as i64 <= 0 {
+                        Interpreter::error(&positions, ip, &return_stack, format!("Can't allocate {} cells", size.1 as i64));
                     }
-                    let addr_map = memory_map.get(&addr.as_ref().unwrap().1);
-                    if addr_map.is_none() {
-                        memory.push(value.unwrap().1);
-                        memory_map.insert(addr.as_ref().unwrap().1, memory.len());
-                        memory_offset += 1;
-                    } else {
-                        memory[*addr_map.unwrap()] = value.unwrap().1;
+                    stack.push((lexer::Types::Pointer, memory.allocate(size.1, ip)));
+                },
+                lexer::OpCodes::Free => {
+                    let addr = stack.pop().unwrap();
+                    if !memory.free(addr.1) {
+                        Interpreter::error(&positions, ip, &return_stack, format!("Free: {} is not the start of a live allocation", addr.1));
                     }
                 },
                 lexer::OpCodes::Cast(value_type) => {
-                    let value = stack.pop();
-                    if value.is_none() {
-                        println!("Stack underflow");
-                        process::exit(1);
-                    }
-                    match value_type {
-                        lexer::Types::Int => {
-                            stack.push((lexer::Types::Int, value.unwrap().1));
-                        },
-                        lexer::Types::Pointer => {
-                            stack.push((lexer::Types::Pointer, value.unwrap().1));
-                        },
-                        lexer::Types::Bool => {
-                            stack.push((lexer::Types::Bool, value.unwrap().1));
-                        },
-                        _ => {},
-                    }
+                    let value = stack.pop().unwrap();
+                    stack.push(Interpreter::cast(value, value_type));
                 },
                 lexer::OpCodes::Push(push_type, push_int, push_str) => {
                     match push_type {
-                        lexer::Types::Int => {
-                            stack.push((lexer::Types::Int, *push_int));
-                        },
                         lexer::Types::String => {
+                            if let Some(base_addr) = literals.get(&ip) {
+                                stack.push((lexer::Types::Pointer, *base_addr));
+                                ip += 1;
+                                continue;
+                            }
                             let bytes = push_str.bytes().collect::<Vec<u8>>();
-                            let base_addr = memory_offset;
+                            let mut string: Vec<u64> = Vec::new();
                             let mut flag = "";
                             for byte in bytes {
                                 if flag == "escape" {
-                                    let character = String::from_utf8(vec![byte]).unwrap();
-                                    if character == "\\" {
-                                        memory.push(92);
-                                        memory_map.insert(memory_offset, memory.len());
-                                        memory_offset += 1;
+                                    if byte == b'\\' {
+                                        string.push(92);
                                         continue;
-                                    } else if character == "n" {
-                                        memory.push(10);
-                                        memory_map.insert(memory_offset, memory.len());
-                                        memory_offset += 1;
+                                    } else if byte == b'n' {
+                                        string.push(10);
                                         continue;
                                     }
                                     flag = "";
-                                } else if String::from_utf8(vec![byte]).unwrap().as_str() == "\\" {
+                                } else if byte == b'\\' {
                                     flag = "escape";
                                     continue;
                                 }
-                                memory.push(byte.into());
-                                memory_map.insert(memory_offset, memory.len());
-                                memory_offset += 1;
+                                string.push(byte.into());
+                            }
+                            let base_addr = memory.reserve(string.len() as u64 + 1);
+                            for (offset, byte) in string.iter().enumerate() {
+                                memory.store(base_addr + offset as u64, *byte);
                             }
-                            memory.push(0);
-                            memory_map.insert(memory_offset, memory.len());
-                            memory_offset += 1;
+                            literals.insert(ip, base_addr);
                             stack.push((lexer::Types::Pointer, base_addr));
                         },
-                        lexer::Types::Bool => {
-                            stack.push((lexer::Types::Bool, *push_int));
+                        _ => {
+                            stack.push((push_type.clone(), *push_int));
                         },
-                        _ => { /* This is only here because you cant push raw pointers */ },
                     }
                 },
                 lexer::OpCodes::Print => {
-                    let value = stack.pop();
-                    if value.is_none() {
-                        println!("Print: Expected one argument on the stack");
-                        process::exit(1);
-                    }
-                    let value = value.unwrap();
+                    let value = stack.pop().unwrap();
                     match value.0 {
-                        lexer::Types::Int => { println!("{}", value.1); },
-                        lexer::Types::Pointer => { println!("{}", value.1); },
+                        integer_type if integer_type.is_integer() => { println!("{}", integer_type.to_integer(value.1)); },
+                        lexer::Types::Float => { println!("{:?}", f64::from_bits(value.1)); },
+                        lexer::Types::Pointer | lexer::Types::Struct(_) => { println!("{}", value.1); },
+                        lexer::Types::Procedure(..) | lexer::Types::Quotation(_) => {
+                            match references.get(&value.1) {
+                                Some(name) => println!("&{}", name),
+                                None => println!("{}", value.1),
+                            }
+                        },
+                        lexer::Types::Enum(name) => {
+                            match enums.get(&name).unwrap().get(value.1 as usize) {
+                                Some(variant) => println!("{}.{}", name, variant),
+                                None => println!("{}({})", name, value.1),
+                            }
+                        },
                         lexer::Types::Bool => {
                             if value.1 == 1 {
                                 println!("true");
@@ -309,65 +758,68 @@ impl Interpreter {
                     }
                 },
                 lexer::OpCodes::Arithmetic(operator) => {
-                    Interpreter::expect(
-                        &stack,
-                        vec![lexer::Types::Int, lexer::Types::Int]
-                    );
-                    let value1 = stack.pop();
-                    let value2 = stack.pop();
-                    match operator {
-                        lexer::Arithmetic::Plus => {
-                            stack.push((lexer::Types::Int, value2.unwrap().1 + value1.unwrap().1));
-                        },
-                        lexer::Arithmetic::Minus => {
-                            stack.push((lexer::Types::Int, value2.unwrap().1 - value1.unwrap().1));
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    match Interpreter::arithmetic(operator, &value2, &value1) {
+                        Ok(result) => stack.push(result),
+                        Err(message) => Interpreter::error(&positions, ip, &return_stack, message),
+                    }
+                },
+                lexer::OpCodes::DivMod => {
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    let quotient = Interpreter::arithmetic(&lexer::Arithmetic::Div, &value2, &value1);
+                    let remainder = Interpreter::arithmetic(&lexer::Arithmetic::Mod, &value2, &value1);
+                    match (quotient, remainder) {
+                        (Ok(quotient), Ok(remainder)) => {
+                            stack.push(quotient);
+                            stack.push(remainder);
                         },
-                        lexer::Arithmetic::Mul => {
-                            stack.push((lexer::Types::Int, value2.unwrap().1 * value1.unwrap().1));
+                        (Err(message), _) | (_, Err(message)) => Interpreter::error(&positions, ip, &return_stack, message),
+                    }
+                },
+                lexer::OpCodes::Bitwise(operator) => {
+                    let result = match operator {
+                        lexer::Bitwise::Not => {
+                            let value = stack.pop().unwrap();
+                            Interpreter::bitwise(operator, &value, &value)
                         },
-                        lexer::Arithmetic::Div => {
-                            stack.push((lexer::Types::Int, value2.unwrap().1 / value1.unwrap().1));
+                        _ => {
+                            let value1 = stack.pop().unwrap();
+                            let value2 = stack.pop().unwrap();
+                            Interpreter::bitwise(operator, &value2, &value1)
                         },
-                        _ => {},
+                    };
+                    match result {
+                        Ok(result) => stack.push(result),
+                        Err(message) => Interpreter::error(&positions, ip, &return_stack, message),
                     }
                 },
                 lexer::OpCodes::Equality(operator) => {
-                    Interpreter::expect(
-                        &stack,
-                        vec![lexer::Types::Int, lexer::Types::Int]
-                    );
-                    let value1 = stack.pop();
-                    let value2 = stack.pop();
-                    match operator {
-                        lexer::Equality::Equal => {
-                            if value1.unwrap().1 == value2.unwrap().1 {
-                                stack.push((lexer::Types::Bool, 1));
-                            } else {
-                                stack.push((lexer::Types::Bool, 0));
-                            }
-                        },
-                        lexer::Equality::Bigger => {
-                            if value1.unwrap().1 < value2.unwrap().1 {
-                                stack.push((lexer::Types::Bool, 1));
-                            } else {
-                                stack.push((lexer::Types::Bool, 0));
-                            }
+                    let value1 = stack.pop().unwrap();
+                    let value2 = stack.pop().unwrap();
+                    match Interpreter::compare(operator, &value2, &value1) {
+                        Ok(result) => stack.push(result),
+                        Err(message) => Interpreter::error(&positions, ip, &return_stack, message),
+                    }
+                },
+                lexer::OpCodes::Logic(operator) => {
+                    let result = match operator {
+                        lexer::Logic::Not => {
+                            stack.pop().unwrap().1 == 0
                         },
-                        lexer::Equality::Smaller => {
-                            if value1.unwrap().1 > value2.unwrap().1 {
-                                stack.push((lexer::Types::Bool, 1));
-                            } else {
-                                stack.push((lexer::Types::Bool, 0));
+                        _ => {
+                            let value1 = stack.pop().unwrap().1 != 0;
+                            let value2 = stack.pop().unwrap().1 != 0;
+                            match operator {
+                                lexer::Logic::And => value2 && value1,
+                                _ => value2 || value1,
                             }
                         },
-                        _ => {},
-                    }
+                    };
+                    stack.push((lexer::Types::Bool, result as u64));
                 },
                 lexer::OpCodes::If => {
-                    Interpreter::expect(
-                        &stack,
-                        vec![lexer::Types::Bool]
-                    );
                     let value = stack.pop();
 
                     if value.unwrap().1 == 0 {
@@ -378,8 +830,13 @@ impl Interpreter {
             }
             ip += 1;
         }
-        println!("stack: {:?}", stack);
-        println!("memory: {:?}", memory);
+        if args.debug {
+            println!("stack: {:?}", stack);
+            println!("memory: {:?}", memory.cells);
+        }
+        if args.leak_check {
+            memory.report_leaks(&positions);
+        }
     }
 }
 
@@ -1,46 +1,204 @@
+use std::fmt;
 use std::fs::File;
 use std::process;
 use std::io::BufReader;
 use std::io::prelude::*;
 use std::collections::HashMap;
 
+use crate::interpreter::Interpreter;
+
+/// Words that open a block closed by `end`, needed to find where a macro body ends
+const BLOCK_KEYWORDS: [&str; 11] = ["proc", "if", "let", "memory", "struct", "enum", "const", "macro", "match", "while", "try"];
+
 
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Types {
     Int,
+    Uint,
+    I8,
+    I16,
+    I32,
+    U8,
+    U16,
+    U32,
+    Float,
     Bool,
     String,
     Pointer,
+    Struct(String),
+    Enum(String),
+    Procedure(Vec<Types>, Vec<Types>),
+    Quotation(String),
     Unknown,
 }
 
+impl Types {
+    pub fn from_name(name: &str) -> Option<Types> {
+        match name {
+            "int" => Some(Types::Int),
+            "uint" => Some(Types::Uint),
+            "i8" => Some(Types::I8),
+            "i16" => Some(Types::I16),
+            "i32" => Some(Types::I32),
+            "u8" => Some(Types::U8),
+            "u16" => Some(Types::U16),
+            "u32" => Some(Types::U32),
+            "float" => Some(Types::Float),
+            "bool" => Some(Types::Bool),
+            "ptr" => Some(Types::Pointer),
+            _ => None,
+        }
+    }
+
+    pub fn is_integer(&self) -> bool {
+        matches!(self, Types::Int | Types::Uint | Types::I8 | Types::I16 | Types::I32 | Types::U8 | Types::U16 | Types::U32)
+    }
+
+    pub fn is_signed(&self) -> bool {
+        matches!(self, Types::Int | Types::I8 | Types::I16 | Types::I32)
+    }
+
+    /// How many of the 64 bits of a stack value the type uses
+    pub fn bits(&self) -> u32 {
+        match self {
+            Types::I8 | Types::U8 => 8,
+            Types::I16 | Types::U16 => 16,
+            Types::I32 | Types::U32 => 32,
+            _ => 64,
+        }
+    }
+
+    /// The smallest and largest value an integer of this type can hold
+    pub fn bounds(&self) -> (i128, i128) {
+        let bits = self.bits();
+        if self.is_signed() {
+            (-(1 << (bits-1)), (1 << (bits-1)) - 1)
+        } else {
+            (0, (1 << bits) - 1)
+        }
+    }
+
+    /// Reads the raw bits of a stack value as the integer they stand for
+    pub fn to_integer(&self, value: u64) -> i128 {
+        let shift = 64 - self.bits();
+        if self.is_signed() {
+            ((value << shift) as i64 >> shift) as i128
+        } else {
+            ((value << shift) >> shift) as i128
+        }
+    }
+
+    /// Wraps an integer into the width of this type and returns its raw bits
+    pub fn wrap_integer(&self, value: i128) -> u64 {
+        self.to_integer(value as u64) as u64
+    }
+}
+
 #[derive(Debug)]
 pub enum Arithmetic {
     Plus,
     Minus,
     Mul,
     Div,
+    Mod,
+    WrappingPlus,
+    WrappingMinus,
+    WrappingMul,
+    SaturatingPlus,
+    SaturatingMinus,
+    SaturatingMul,
+}
+
+impl Arithmetic {
+    /// The type `lhs rhs <operator>` leaves on the stack, if the operands are allowed
+    pub fn result_type(&self, lhs: &Types, rhs: &Types) -> Option<Types> {
+        match (self, lhs, rhs) {
+            (_, lhs, rhs) if lhs == rhs && lhs.is_integer() => Some(lhs.clone()),
+            (Arithmetic::Plus | Arithmetic::Minus | Arithmetic::Mul | Arithmetic::Div | Arithmetic::Mod, Types::Float, Types::Float) => Some(Types::Float),
+            (Arithmetic::Plus | Arithmetic::Minus, Types::Pointer, Types::Int) => Some(Types::Pointer),
+            (Arithmetic::Minus, Types::Pointer, Types::Pointer) => Some(Types::Int),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Bitwise {
+    And,
+    Or,
+    Xor,
+    Not,
+    Shl,
+    Shr,
+    Sar,
+}
+
+impl Bitwise {
+    /// The type `lhs rhs <operator>` leaves on the stack, if the operands are allowed.
+    /// Shifts take any integer as the amount, the other operators need matching types
+    pub fn result_type(&self, lhs: &Types, rhs: &Types) -> Option<Types> {
+        match (self, lhs, rhs) {
+            (Bitwise::Shl | Bitwise::Shr | Bitwise::Sar, lhs, rhs) if lhs.is_integer() && rhs.is_integer() => Some(lhs.clone()),
+            (Bitwise::Shl | Bitwise::Shr | Bitwise::Sar, _, _) => None,
+            (_, lhs, rhs) if lhs == rhs && lhs.is_integer() => Some(lhs.clone()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug)]
 pub enum Equality {
     Equal,
+    NotEqual,
     Bigger,
+    BiggerEqual,
     Smaller,
+    SmallerEqual,
+}
+
+impl Equality {
+    /// Both sides have to be of the same type, which is compared by its integer value
+    pub fn accepts(&self, lhs: &Types, rhs: &Types) -> bool {
+        lhs == rhs && (lhs.is_integer() || matches!(lhs, Types::Float | Types::Bool | Types::Pointer | Types::Struct(_) | Types::Enum(_)))
+    }
+}
+
+#[derive(Debug)]
+pub enum Logic {
+    And,
+    Or,
+    Not,
 }
 
 #[derive(Debug)]
+#[allow(non_snake_case)]
 pub struct Procedure {
     pub Proc: String,
     pub Location: usize,
     pub ParameterTypes: Vec<Types>,
     pub ReturnTypes: Vec<Types>,
+    pub FrameSize: u64,
+}
+
+impl Procedure {
+    /// Quotations are hidden procedures named after where they are written
+    pub fn is_quotation(&self) -> bool {
+        self.Proc.starts_with('[')
+    }
 }
 
 #[derive(Debug)]
 pub enum ScopeTypes {
     Proc,
     If,
+    Let(usize),
+    Const(String),
+    Match { cases: Vec<usize>, exits: Vec<usize>, default: bool },
+    While(usize),
+    Loop { condition: usize, breaks: Vec<usize> },
+    Try(usize),
+    Catch(usize),
+    Quotation { skip: usize, bindings: Vec<String>, buffers: HashMap<String, u64>, labels: HashMap<String, Label>, gotos: Vec<Goto> },
 }
 
 #[derive(Debug)]
@@ -51,17 +209,33 @@ pub enum Syscalls {
 }
 
 #[derive(Debug)]
+#[allow(clippy::upper_case_acronyms)]
 pub enum OpCodes {
     Push(Types, u64, String),
 
     Arithmetic(Arithmetic),
+    DivMod,
+    Bitwise(Bitwise),
     Equality(Equality),
+    Logic(Logic),
 
     Cast(Types),
 
     Ident(String),
+    Reference(String),
+    Call,
 
-    Goto(String),
+    Bind(usize),
+    Unbind(usize),
+    Local(usize),
+    Buffer(u64),
+
+    Goto(usize),
+    Case(Types, u64, usize),
+    NoMatch(Vec<u64>),
+    Try(usize),
+    EndTry,
+    Throw,
 
     Syscalls(Syscalls),
 
@@ -69,6 +243,11 @@ pub enum OpCodes {
 
     Load,
     Store,
+    FieldLoad(Types, u64, Types),
+    FieldStore(Types, u64, Types),
+
+    Alloc,
+    Free,
 
     Dup,
     Swap,
@@ -81,28 +260,321 @@ pub enum OpCodes {
     Print,
 }
 
+#[derive(Debug, Clone)]
+pub struct Position {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub tokens: Vec<OpCodes>,
+    pub positions: Vec<Position>,
+    pub scopes: HashMap<usize, usize>,
+    pub procedures: HashMap<String, Procedure>,
+    pub globals: HashMap<String, (u64, u64)>,
+    pub data_size: u64,
+    pub structs: HashMap<String, Vec<(String, Types)>>,
+    pub enums: HashMap<String, Vec<String>>,
+    pub consts: HashMap<String, (Types, u64)>,
+    pub macros: HashMap<String, (String, Vec<String>)>,
+}
+
+/// A label of the procedure being lexed, `path` holds the blocks it is declared in
+#[derive(Debug)]
+pub struct Label {
+    target: usize,
+    path: Vec<(usize, usize, bool)>,
+}
+
+/// A goto that is resolved once the procedure it is in has been lexed completely, `unbind`
+/// is the `Unbind` in front of it when it is inside a let block
+#[derive(Debug)]
+pub struct Goto {
+    index: usize,
+    unbind: Option<usize>,
+    label: String,
+    position: Position,
+    path: Vec<(usize, usize, bool)>,
+}
+
+/// A macro body that has been spliced into the source and is still being lexed
+struct Expansion {
+    name: String,
+    end: usize,
+    id: usize,
+    labels: Vec<String>,
+    position: Position,
+}
+
 pub struct Lexer;
 
 impl Lexer {
-    pub fn tokenize(file: &str) -> (Vec<OpCodes>, HashMap<usize, usize>, HashMap<String, Procedure>, HashMap<String, usize>) {
+    /// Resolves a type name, the name of a struct stands for a pointer to that struct
+    pub fn parse_type(name: &str, structs: &HashMap<String, Vec<(String, Types)>>, enums: &HashMap<String, Vec<String>>) -> Option<Types> {
+        if structs.contains_key(name) {
+            return Some(Types::Struct(name.to_string()));
+        } else if enums.contains_key(name) {
+            return Some(Types::Enum(name.to_string()));
+        } else if let Some(signature) = name.strip_prefix("proc(").and_then(|signature| signature.strip_suffix(')')) {
+            return Lexer::parse_signature(signature, structs, enums);
+        }
+        Types::from_name(name)
+    }
+
+    /// Parses the inside of a `proc(int,int:bool)` type, the parameter and return
+    /// types are listed like in a procedure declaration
+    fn parse_signature(signature: &str, structs: &HashMap<String, Vec<(String, Types)>>, enums: &HashMap<String, Vec<String>>) -> Option<Types> {
+        let mut lists: (Vec<Types>, Vec<Types>) = (Vec::new(), Vec::new());
+        let mut returns = false;
+        let mut depth = 0;
+        let mut name = String::new();
+        for character in signature.chars().chain([',']) {
+            match character {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                ',' | ':' if depth == 0 => {
+                    if !name.is_empty() {
+                        let parsed = Lexer::parse_type(&name, structs, enums)?;
+                        if returns {
+                            lists.1.push(parsed);
+                        } else {
+                            lists.0.push(parsed);
+                        }
+                        name = String::new();
+                    }
+                    if character == ':' {
+                        if returns {
+                            return None;
+                        }
+                        returns = true;
+                    }
+                    continue;
+                },
+                _ => {},
+            }
+            name.push(character);
+        }
+        Some(Types::Procedure(lists.0, lists.1))
+    }
+
+    /// Labels declared inside a macro are renamed per expansion, so that using
+    /// the macro twice doesn't declare the same label twice
+    fn hygienic(label: String, expansions: &[Expansion]) -> String {
+        match expansions.last() {
+            Some(expansion) if expansion.labels.contains(&label) => format!("{}@{}", label, expansion.id),
+            _ => label,
+        }
+    }
+
+    /// A size is either an integer literal or the name of an integer constant
+    pub fn parse_size(token: &str, consts: &HashMap<String, (Types, u64)>) -> Option<u64> {
+        match consts.get(token) {
+            Some((const_type, value)) if const_type.is_integer() => Some(*value),
+            Some(_) => None,
+            None => token.parse::<u64>().ok(),
+        }
+    }
+
+    /// Parses the literal of a `case`, a number, a boolean, a constant or an enum variant
+    pub fn parse_literal(token: &str, consts: &HashMap<String, (Types, u64)>, enums: &HashMap<String, Vec<String>>) -> Option<(Types, u64)> {
+        if let Some(literal) = Lexer::parse_number(token) {
+            return Some(literal);
+        } else if let Some(literal) = consts.get(token) {
+            return Some(literal.clone());
+        }
+        match token {
+            "true" => Some((Types::Bool, 1)),
+            "false" => Some((Types::Bool, 0)),
+            _ => {
+                let (name, variant) = token.split_once('.')?;
+                let value = enums.get(name)?.iter().position(|known| known == variant)?;
+                Some((Types::Enum(name.to_string()), value as u64))
+            },
+        }
+    }
+
+    /// Parses an int, float or `123u` uint literal
+    pub fn parse_number(token: &str) -> Option<(Types, u64)> {
+        if let Ok(int_token) = token.parse::<i64>() {
+            Some((Types::Int, int_token as u64))
+        } else if let (true, Ok(float_token)) = (token.contains('.') && token.chars().all(|c| "0123456789.-eE".contains(c)), token.parse::<f64>()) {
+            Some((Types::Float, float_token.to_bits()))
+        } else if let Some(Ok(uint_token)) = token.strip_suffix('u').map(|token| token.parse::<u64>()) {
+            Some((Types::Uint, uint_token))
+        } else {
+            None
+        }
+    }
+
+    /// Points the `Case` at `case` to the next instruction to be emitted, which is tried
+    /// when its literal doesn't match
+    fn patch_case(tokens: &mut [OpCodes], case: usize) {
+        let next = tokens.len();
+        if let OpCodes::Case(_, _, target) = &mut tokens[case] {
+            *target = next;
+        }
+    }
+
+    /// Finds the innermost loop for `break` and `continue`, together with the number of
+    /// bindings of the let blocks and the number of try blocks that are left on the way
+    fn innermost_loop(scope: &mut [(usize, ScopeTypes, Position)]) -> Option<(usize, &mut Vec<usize>, usize, usize)> {
+        let mut unbind = 0;
+        let mut tries = 0;
+        for (_, scope_type, _) in scope.iter_mut().rev() {
+            match scope_type {
+                ScopeTypes::Loop { condition, breaks } => return Some((*condition, breaks, unbind, tries)),
+                ScopeTypes::Let(count) => unbind += *count,
+                ScopeTypes::Try(_) => tries += 1,
+                ScopeTypes::Proc | ScopeTypes::Quotation { .. } => return None,
+                _ => {},
+            }
+        }
+        None
+    }
+
+    /// The let, while, try, catch and match blocks of the current procedure that enclose
+    /// the current position, outermost first and each with the number of names it binds and
+    /// whether it is a try block
+    fn block_path(scope: &[(usize, ScopeTypes, Position)]) -> Vec<(usize, usize, bool)> {
+        let mut path = Vec::new();
+        for (start, scope_type, _) in scope.iter().rev() {
+            match scope_type {
+                ScopeTypes::Proc | ScopeTypes::Quotation { .. } => break,
+                ScopeTypes::Let(count) => path.push((*start, *count, false)),
+                ScopeTypes::While(_) | ScopeTypes::Loop { .. } | ScopeTypes::Catch(_) | ScopeTypes::Match { .. } => path.push((*start, 0, false)),
+                ScopeTypes::Try(_) => path.push((*start, 0, true)),
+                _ => {},
+            }
+        }
+        path.reverse();
+        path
+    }
+
+    /// Points every goto of a procedure at its label once the whole body is known. A goto may
+    /// leave blocks but not jump into one, the bindings of the let blocks it leaves are
+    /// dropped first. It may not leave a try block since that would keep its handler installed
+    fn resolve_gotos(tokens: &mut [OpCodes], gotos: Vec<Goto>, labels: &HashMap<String, Label>, procedure: &str) {
+        for goto in gotos {
+            let label = labels.get(&goto.label);
+            if label.is_none() {
+                println!("{}: Unknown label {} in procedure {}", goto.position, goto.label, procedure);
+                process::exit(1);
+            }
+            let label = label.unwrap();
+            if !goto.path.starts_with(&label.path) {
+                println!("{}: goto {} jumps into a block", goto.position, goto.label);
+                process::exit(1);
+            }
+            let left = &goto.path[label.path.len()..];
+            if left.iter().any(|(_, _, is_try)| *is_try) {
+                println!("{}: goto {} leaves a try block", goto.position, goto.label);
+                process::exit(1);
+            }
+            if let Some(unbind) = goto.unbind {
+                tokens[unbind] = OpCodes::Unbind(left.iter().map(|(_, count, _)| count).sum());
+            }
+            tokens[goto.index] = OpCodes::Goto(label.target);
+        }
+    }
+
+    /// Splits `Struct.field@` and `Struct.field!` into the struct type, the offset and type
+    /// of the field and whether the field is stored to
+    pub fn field_access(token: &str, structs: &HashMap<String, Vec<(String, Types)>>) -> Option<(Types, u64, Types, bool)> {
+        let (access, store) = match token.strip_suffix('@') {
+            Some(access) => (access, false),
+            None => (token.strip_suffix('!')?, true),
+        };
+        let (name, field) = access.split_once('.')?;
+        let fields = structs.get(name)?;
+        let offset = fields.iter().position(|(field_name, _)| field_name == field)?;
+        Some((Types::Struct(name.to_string()), offset as u64, fields[offset].1.clone(), store))
+    }
+
+    pub fn tokenize(file: &str) -> Program {
         let mut tokens: Vec<OpCodes> = Vec::new();
+        let mut positions: Vec<Position> = Vec::new();
         let fd = File::open(file);
         if fd.is_err() {
             println!("Failed to open file: {}", file);
             process::exit(1);
         }
-        let buffer = BufReader::new(fd.unwrap());
+        let mut source: Vec<u8> = Vec::new();
+        if BufReader::new(fd.unwrap()).read_to_end(&mut source).is_err() {
+            println!("Failed to read file: {}", file);
+            process::exit(1);
+        }
+        let mut index = 0;
         let mut token = String::new();
 
-        let mut scope: Vec<(usize, ScopeTypes)> = Vec::new();
+        let mut scope: Vec<(usize, ScopeTypes, Position)> = Vec::new();
         let mut scopes: HashMap<usize, usize> = HashMap::new();
         let mut procedures: (HashMap<String, Procedure>, Vec<Procedure>) = (HashMap::new(), Vec::new());
-        let mut labels: HashMap<String, usize> = HashMap::new();
+        let mut labels: HashMap<String, Label> = HashMap::new();
+        let mut gotos: Vec<Goto> = Vec::new();
+        let mut globals: HashMap<String, (u64, u64)> = HashMap::new();
+        let mut data_size: u64 = 0;
+        let mut global_name = String::new();
+        let mut structs: HashMap<String, Vec<(String, Types)>> = HashMap::new();
+        let mut struct_name = String::new();
+        let mut field_name = String::new();
+        let mut enums: HashMap<String, Vec<String>> = HashMap::new();
+        let mut enum_name = String::new();
+        let mut consts: HashMap<String, (Types, u64)> = HashMap::new();
+        let mut macros: HashMap<String, (String, Vec<String>)> = HashMap::new();
+        let mut macro_name = String::new();
+        let mut macro_body = String::new();
+        let mut macro_labels: Vec<String> = Vec::new();
+        let (mut macro_depth, mut macro_string, mut macro_word) = (0, false, String::new());
+        let mut expansions: Vec<Expansion> = Vec::new();
+        let mut expansion_count = 0;
+        let mut quotation_count = 0;
+        let mut bindings: Vec<String> = Vec::new();
+        let mut let_start = 0;
+        let mut buffers: HashMap<String, u64> = HashMap::new();
+        let mut buffer_name = String::new();
 
         let mut flag = "";
 
-        for byte in buffer.bytes() {
-            let character = String::from_utf8(vec![byte.unwrap()]).unwrap();
+        let (mut line, mut column) = (1, 1);
+        let mut position = Position { file: file.to_string(), line, column };
+
+        while index < source.len() {
+            while expansions.last().is_some_and(|expansion| expansion.end <= index) {
+                expansions.pop();
+            }
+            let width = match source[index] {
+                0xf0.. => 4,
+                0xe0.. => 3,
+                0xc0.. => 2,
+                _ => 1,
+            };
+            let character = String::from_utf8_lossy(&source[index..source.len().min(index + width)]).into_owned();
+            index += width;
+            while positions.len() < tokens.len() {
+                positions.push(position.clone());
+            }
+            if token.is_empty() && flag != "string" {
+                position = match expansions.last() {
+                    Some(expansion) => expansion.position.clone(),
+                    None => Position { file: file.to_string(), line, column },
+                };
+            }
+            if expansions.is_empty() {
+                if character == "\n" {
+                    line += 1;
+                    column = 1;
+                } else {
+                    column += 1;
+                }
+            }
             if flag == "string" {
                 if character == "\"" {
                     tokens.push(OpCodes::Push(Types::String, 0, token));
@@ -115,10 +587,27 @@ impl Lexer {
             } else if flag == "use" {
                 if character == " " || character == "\n" {
                     let used = Lexer::tokenize(&token);
-                    tokens.extend(used.0);
-                    scopes.extend(used.1);
-                    procedures.0.extend(used.2);
-                    labels.extend(used.3);
+                    let offset = tokens.len();
+                    tokens.extend(used.tokens.into_iter().map(|token| match token {
+                        OpCodes::Goto(target) => OpCodes::Goto(target + offset),
+                        OpCodes::Case(case_type, value, next) => OpCodes::Case(case_type, value, next + offset),
+                        OpCodes::Try(catch) => OpCodes::Try(catch + offset),
+                        token => token,
+                    }));
+                    positions.extend(used.positions);
+                    scopes.extend(used.scopes.into_iter().map(|(start, end)| (start + offset, end + offset)));
+                    for (name, mut procedure) in used.procedures {
+                        procedure.Location += offset;
+                        procedures.0.insert(name, procedure);
+                    }
+                    for (name, (global_offset, size)) in used.globals {
+                        globals.insert(name, (global_offset + data_size, size));
+                    }
+                    data_size += used.data_size;
+                    structs.extend(used.structs);
+                    enums.extend(used.enums);
+                    consts.extend(used.consts);
+                    macros.extend(used.macros);
                     flag = "";
                     token = String::new();
                     continue;
@@ -133,6 +622,7 @@ impl Lexer {
                             Location: 0,
                             ParameterTypes: Vec::new(),
                             ReturnTypes: Vec::new(),
+                            FrameSize: 0,
                         }
                     );
                     flag = "ParameterTypes";
@@ -143,24 +633,19 @@ impl Lexer {
                 continue;
             } else if flag == "ParameterTypes" {
                 if character == " " || character == "\n" {
-                    let mut parameter_type = Types::Int;
-                    match token.as_str() {
-                        "int" => {
-                            parameter_type = Types::Int;
-                        },
-                        "bool" => {
-                            parameter_type = Types::Bool;
-                        },
-                        "ptr" => {
-                            parameter_type = Types::Pointer;
-                        },
-                        _ => {},
+                    if token.is_empty() {
+                        continue;
+                    }
+                    let parameter_type = Lexer::parse_type(&token, &structs, &enums);
+                    if parameter_type.is_none() {
+                        println!("{}: Unknown type: {}", position, token);
+                        process::exit(1);
                     }
                     let proc_len = procedures.1.len()-1;
-                    procedures.1[proc_len].ParameterTypes.push(parameter_type);
+                    procedures.1[proc_len].ParameterTypes.push(parameter_type.unwrap());
                     token = String::new();
                     continue;
-                } else if character == ":" {
+                } else if character == ":" && token.matches('(').count() == token.matches(')').count() {
                     flag = "ReturnTypes";
                     token = String::new();
                     continue;
@@ -178,30 +663,26 @@ impl Lexer {
                                 Location: tokens.len(),
                                 ParameterTypes: procedures.1[proc_len].ParameterTypes.clone(),
                                 ReturnTypes: procedures.1[proc_len].ReturnTypes.clone(),
+                                FrameSize: 0,
                             }
                         );
-                        scope.push((tokens.len(), ScopeTypes::Proc));
+                        scope.push((tokens.len(), ScopeTypes::Proc, position.clone()));
+                        bindings.clear();
+                        buffers.clear();
+                        labels.clear();
                         flag = "";
                         token = String::new();
                         continue;
-                    } else if token == "" {
+                    } else if token.is_empty() {
                         continue;
                     }
-                    let mut return_type = Types::Int;
-                    match token.as_str() {
-                        "int" => {
-                            return_type = Types::Int;
-                        },
-                        "bool" => {
-                            return_type = Types::Bool;
-                        },
-                        "ptr" => {
-                            return_type = Types::Pointer;
-                        },
-                        _ => {  },
+                    let return_type = Lexer::parse_type(&token, &structs, &enums);
+                    if return_type.is_none() {
+                        println!("{}: Unknown type: {}", position, token);
+                        process::exit(1);
                     }
                     let proc_len = procedures.1.len()-1;
-                    procedures.1[proc_len].ReturnTypes.push(return_type);
+                    procedures.1[proc_len].ReturnTypes.push(return_type.unwrap());
                     token = String::new();
                     continue;
                 }
@@ -209,20 +690,270 @@ impl Lexer {
                 continue;
             } else if flag == "goto" {
                 if character == " " || character == "\n" {
-                    tokens.push(OpCodes::Goto(token));
+                    if !scope.iter().any(|scope| matches!(scope.1, ScopeTypes::Proc)) {
+                        println!("{}: goto used outside of a procedure", position);
+                        process::exit(1);
+                    }
+                    let path = Lexer::block_path(&scope);
+                    let mut unbind = None;
+                    if path.iter().any(|(_, count, _)| *count > 0) {
+                        unbind = Some(tokens.len());
+                        tokens.push(OpCodes::Unbind(0));
+                    }
+                    gotos.push(Goto {
+                        index: tokens.len(),
+                        unbind,
+                        label: Lexer::hygienic(token, &expansions),
+                        position: position.clone(),
+                        path,
+                    });
+                    tokens.push(OpCodes::Goto(0));
                     token = String::new();
                     flag = "";
                     continue;
                 }
                 token = token + &character;
                 continue;
+            } else if flag == "let" {
+                if character == " " || character == "\n" {
+                    if token == "in" {
+                        let count = bindings.len() - let_start;
+                        if count == 0 {
+                            println!("{}: Expected at least one name to bind", position);
+                            process::exit(1);
+                        }
+                        tokens.push(OpCodes::Bind(count));
+                        scope.push((tokens.len(), ScopeTypes::Let(count), position.clone()));
+                        flag = "";
+                    } else if !token.is_empty() {
+                        bindings.push(token);
+                    }
+                    token = String::new();
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "local" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        buffer_name = token;
+                        flag = "local_size";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "local_size" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        let size = Lexer::parse_size(&token, &consts);
+                        if size.is_none() {
+                            println!("{}: Expected a size for local {} but got: {}", position, buffer_name, token);
+                            process::exit(1);
+                        }
+                        let procedure = procedures.0.get_mut(&procedures.1.last().unwrap().Proc).unwrap();
+                        buffers.insert(buffer_name.clone(), procedure.FrameSize);
+                        procedure.FrameSize += size.unwrap();
+                        flag = "";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "struct" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        structs.insert(token.clone(), Vec::new());
+                        struct_name = token;
+                        flag = "struct_fields";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "struct_fields" {
+                if character == " " || character == "\n" {
+                    if token == "end" {
+                        if !field_name.is_empty() {
+                            println!("{}: Field {}.{} has no type", position, struct_name, field_name);
+                            process::exit(1);
+                        }
+                        flag = "";
+                    } else if !token.is_empty() && field_name.is_empty() {
+                        if structs.get(&struct_name).unwrap().iter().any(|field| field.0 == token) {
+                            println!("{}: Field {}.{} is declared twice", position, struct_name, token);
+                            process::exit(1);
+                        }
+                        field_name = token;
+                    } else if !token.is_empty() {
+                        let field_type = Lexer::parse_type(&token, &structs, &enums);
+                        if field_type.is_none() {
+                            println!("{}: Unknown type: {}", position, token);
+                            process::exit(1);
+                        }
+                        structs.get_mut(&struct_name).unwrap().push((field_name, field_type.unwrap()));
+                        field_name = String::new();
+                    }
+                    token = String::new();
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "enum" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        enums.insert(token.clone(), Vec::new());
+                        enum_name = token;
+                        flag = "enum_variants";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "enum_variants" {
+                if character == " " || character == "\n" {
+                    if token == "end" {
+                        flag = "";
+                    } else if !token.is_empty() {
+                        let variants = enums.get_mut(&enum_name).unwrap();
+                        if variants.contains(&token) {
+                            println!("{}: Variant {}.{} is declared twice", position, enum_name, token);
+                            process::exit(1);
+                        }
+                        variants.push(token);
+                    }
+                    token = String::new();
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "case" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        let literal = Lexer::parse_literal(&token, &consts, &enums);
+                        if literal.is_none() {
+                            println!("{}: case expects a literal but got: {}", position, token);
+                            process::exit(1);
+                        }
+                        let (literal_type, value) = literal.unwrap();
+                        if let Some((_, ScopeTypes::Match { cases, .. }, _)) = scope.last_mut() {
+                            cases.push(tokens.len());
+                        }
+                        tokens.push(OpCodes::Case(literal_type, value, 0));
+                        flag = "";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "const" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        scope.push((tokens.len(), ScopeTypes::Const(token), position.clone()));
+                        flag = "";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "macro" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        macro_name = token;
+                        flag = "macro_body";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "macro_body" {
+                macro_body = macro_body + &character;
+                if macro_string || character == "\"" {
+                    macro_string = (character == "\"") != macro_string;
+                    continue;
+                }
+                if character == " " || character == "\n" {
+                    if token == "end" && macro_depth == 0 {
+                        macro_body.truncate(macro_body.len() - 4);
+                        macros.insert(macro_name.clone(), (macro_body, macro_labels));
+                        (macro_body, macro_labels) = (String::new(), Vec::new());
+                        flag = "";
+                    } else if token == "end" {
+                        macro_depth -= 1;
+                    } else if BLOCK_KEYWORDS.contains(&token.as_str()) {
+                        macro_depth += 1;
+                    } else if token.ends_with(':') && token.len() > 1 && macro_word != "goto" {
+                        macro_labels.push(token.clone());
+                    }
+                    if !token.is_empty() {
+                        macro_word = token;
+                    }
+                    token = String::new();
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "memory" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        if globals.contains_key(&token) {
+                            println!("{}: Memory {} is declared twice", position, token);
+                            process::exit(1);
+                        }
+                        global_name = token;
+                        flag = "memory_size";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "memory_size" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        let size = Lexer::parse_size(&token, &consts);
+                        if size.is_none() {
+                            println!("{}: Expected a size for memory {} but got: {}", position, global_name, token);
+                            process::exit(1);
+                        }
+                        let size = size.unwrap();
+                        globals.insert(global_name.clone(), (data_size, size));
+                        data_size += size;
+                        flag = "memory_end";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
+            } else if flag == "memory_end" {
+                if character == " " || character == "\n" {
+                    if !token.is_empty() {
+                        if token != "end" {
+                            println!("{}: Expected end of memory {} but got: {}", position, global_name, token);
+                            process::exit(1);
+                        }
+                        flag = "";
+                        token = String::new();
+                    }
+                    continue;
+                }
+                token = token + &character;
+                continue;
             }
             match character.as_str() {
                 "\"" => {
                     flag = "string";
                 },
                 " " | "\n" => {
-                    if token != "" {
+                    if !token.is_empty() {
                         match token.as_str() {
                             "+" => {
                                 tokens.push(OpCodes::Arithmetic(Arithmetic::Plus));
@@ -236,21 +967,222 @@ impl Lexer {
                             "/" => {
                                 tokens.push(OpCodes::Arithmetic(Arithmetic::Div));
                             },
+                            "%" | "mod" => {
+                                tokens.push(OpCodes::Arithmetic(Arithmetic::Mod));
+                            },
+                            "divmod" => {
+                                tokens.push(OpCodes::DivMod);
+                            },
+                            "and" => {
+                                tokens.push(OpCodes::Bitwise(Bitwise::And));
+                            },
+                            "or" => {
+                                tokens.push(OpCodes::Bitwise(Bitwise::Or));
+                            },
+                            "xor" => {
+                                tokens.push(OpCodes::Bitwise(Bitwise::Xor));
+                            },
+                            "not" => {
+                                tokens.push(OpCodes::Bitwise(Bitwise::Not));
+                            },
+                            "shl" => {
+                                tokens.push(OpCodes::Bitwise(Bitwise::Shl));
+                            },
+                            "shr" => {
+                                tokens.push(OpCodes::Bitwise(Bitwise::Shr));
+                            },
+                            "sar" => {
+                                tokens.push(OpCodes::Bitwise(Bitwise::Sar));
+                            },
+                            "wrapping+" => {
+                                tokens.push(OpCodes::Arithmetic(Arithmetic::WrappingPlus));
+                            },
+                            "wrapping-" => {
+                                tokens.push(OpCodes::Arithmetic(Arithmetic::WrappingMinus));
+                            },
+                            "wrapping*" => {
+                                tokens.push(OpCodes::Arithmetic(Arithmetic::WrappingMul));
+                            },
+                            "saturating+" => {
+                                tokens.push(OpCodes::Arithmetic(Arithmetic::SaturatingPlus));
+                            },
+                            "saturating-" => {
+                                tokens.push(OpCodes::Arithmetic(Arithmetic::SaturatingMinus));
+                            },
+                            "saturating*" => {
+                                tokens.push(OpCodes::Arithmetic(Arithmetic::SaturatingMul));
+                            },
                             "." => {
                                 tokens.push(OpCodes::Print);
                             },
                             "=" => {
                                 tokens.push(OpCodes::Equality(Equality::Equal));
                             },
+                            "!=" => {
+                                tokens.push(OpCodes::Equality(Equality::NotEqual));
+                            },
                             ">" => {
                                 tokens.push(OpCodes::Equality(Equality::Bigger));
                             },
+                            ">=" => {
+                                tokens.push(OpCodes::Equality(Equality::BiggerEqual));
+                            },
                             "<" => {
                                 tokens.push(OpCodes::Equality(Equality::Smaller));
                             },
+                            "<=" => {
+                                tokens.push(OpCodes::Equality(Equality::SmallerEqual));
+                            },
+                            "&&" => {
+                                tokens.push(OpCodes::Logic(Logic::And));
+                            },
+                            "||" => {
+                                tokens.push(OpCodes::Logic(Logic::Or));
+                            },
+                            "!" => {
+                                tokens.push(OpCodes::Logic(Logic::Not));
+                            },
                             "if" => {
                                 tokens.push(OpCodes::If);
-                                scope.push((tokens.len(), ScopeTypes::If));
+                                scope.push((tokens.len(), ScopeTypes::If, position.clone()));
+                            },
+                            "while" => {
+                                scope.push((tokens.len(), ScopeTypes::While(tokens.len()), position.clone()));
+                            },
+                            "do" => {
+                                let (condition, opened) = match scope.pop() {
+                                    Some((_, ScopeTypes::While(condition), opened)) => (condition, opened),
+                                    _ => {
+                                        println!("{}: do used outside of a while", position);
+                                        process::exit(1);
+                                    },
+                                };
+                                tokens.push(OpCodes::If);
+                                scope.push((tokens.len(), ScopeTypes::Loop { condition, breaks: Vec::new() }, opened));
+                            },
+                            "break" | "continue" => {
+                                let innermost = Lexer::innermost_loop(&mut scope);
+                                if innermost.is_none() {
+                                    println!("{}: {} used outside of a loop", position, token);
+                                    process::exit(1);
+                                }
+                                let (condition, breaks, unbind, tries) = innermost.unwrap();
+                                for _ in 0..tries {
+                                    tokens.push(OpCodes::EndTry);
+                                }
+                                if unbind > 0 {
+                                    tokens.push(OpCodes::Unbind(unbind));
+                                }
+                                if token == "break" {
+                                    breaks.push(tokens.len());
+                                }
+                                tokens.push(OpCodes::Goto(condition));
+                            },
+                            "return" => {
+                                if !scope.iter().any(|scope| matches!(scope.1, ScopeTypes::Proc)) {
+                                    println!("{}: return used outside of a procedure", position);
+                                    process::exit(1);
+                                }
+                                tokens.push(OpCodes::Return);
+                            },
+                            "[" => {
+                                if !scope.iter().any(|scope| matches!(scope.1, ScopeTypes::Proc)) {
+                                    println!("{}: Quotation outside of a procedure", position);
+                                    process::exit(1);
+                                }
+                                let name = format!("[{} #{}]", file, quotation_count);
+                                quotation_count += 1;
+                                procedures.0.insert(
+                                    name.clone(),
+                                    Procedure {
+                                        Proc: name.clone(),
+                                        Location: tokens.len() + 1,
+                                        ParameterTypes: Vec::new(),
+                                        ReturnTypes: Vec::new(),
+                                        FrameSize: 0,
+                                    }
+                                );
+                                procedures.1.push(
+                                    Procedure {
+                                        Proc: name,
+                                        Location: tokens.len() + 1,
+                                        ParameterTypes: Vec::new(),
+                                        ReturnTypes: Vec::new(),
+                                        FrameSize: 0,
+                                    }
+                                );
+                                scope.push((tokens.len(), ScopeTypes::Quotation {
+                                    skip: tokens.len(),
+                                    bindings: std::mem::take(&mut bindings),
+                                    buffers: std::mem::take(&mut buffers),
+                                    labels: std::mem::take(&mut labels),
+                                    gotos: std::mem::take(&mut gotos),
+                                }, position.clone()));
+                                tokens.push(OpCodes::Goto(0));
+                            },
+                            "]" => {
+                                match scope.pop() {
+                                    Some((_, ScopeTypes::Quotation { skip, bindings: outer_bindings, buffers: outer_buffers, labels: outer_labels, gotos: outer_gotos }, _)) => {
+                                        let quotation = procedures.1.pop().unwrap();
+                                        Lexer::resolve_gotos(&mut tokens, std::mem::take(&mut gotos), &labels, &quotation.Proc);
+                                        tokens.push(OpCodes::Return);
+                                        tokens[skip] = OpCodes::Goto(tokens.len());
+                                        tokens.push(OpCodes::Reference(quotation.Proc));
+                                        (bindings, buffers, labels, gotos) = (outer_bindings, outer_buffers, outer_labels, outer_gotos);
+                                    },
+                                    _ => {
+                                        println!("{}: Unexpected token: ]", position);
+                                        process::exit(1);
+                                    },
+                                }
+                            },
+                            "try" => {
+                                scope.push((tokens.len(), ScopeTypes::Try(tokens.len()), position.clone()));
+                                tokens.push(OpCodes::Try(0));
+                            },
+                            "catch" => {
+                                let start = match scope.pop() {
+                                    Some((_, ScopeTypes::Try(start), _)) => start,
+                                    _ => {
+                                        println!("{}: catch used outside of a try", position);
+                                        process::exit(1);
+                                    },
+                                };
+                                tokens.push(OpCodes::EndTry);
+                                scope.push((tokens.len(), ScopeTypes::Catch(tokens.len()), position.clone()));
+                                tokens.push(OpCodes::Goto(0));
+                                tokens[start] = OpCodes::Try(tokens.len());
+                            },
+                            "throw" => {
+                                tokens.push(OpCodes::Throw);
+                            },
+                            "match" => {
+                                scope.push((tokens.len(), ScopeTypes::Match { cases: Vec::new(), exits: Vec::new(), default: false }, position.clone()));
+                            },
+                            "case" | "default" => {
+                                match scope.last_mut() {
+                                    Some((_, ScopeTypes::Match { cases, exits, default }, _)) => {
+                                        if *default {
+                                            println!("{}: {} after the default arm of a match", position, token);
+                                            process::exit(1);
+                                        }
+                                        if let Some(last) = cases.last() {
+                                            exits.push(tokens.len());
+                                            tokens.push(OpCodes::Goto(0));
+                                            Lexer::patch_case(&mut tokens, *last);
+                                        }
+                                        if token == "default" {
+                                            tokens.push(OpCodes::Drop);
+                                            *default = true;
+                                        } else {
+                                            flag = "case";
+                                        }
+                                    },
+                                    _ => {
+                                        println!("{}: {} used outside of a match", position, token);
+                                        process::exit(1);
+                                    },
+                                }
                             },
                             "end" => {
                                 let start_scope = scope.pop();
@@ -262,8 +1194,67 @@ impl Lexer {
                                 scopes.insert(start_scope.0, tokens.len());
                                 match start_scope.1 {
                                     ScopeTypes::Proc => {
+                                        Lexer::resolve_gotos(&mut tokens, std::mem::take(&mut gotos), &labels, &procedures.1.last().unwrap().Proc);
                                         tokens.push(OpCodes::Return);
                                     },
+                                    ScopeTypes::Quotation { .. } => {
+                                        println!("{}: Quotation is closed with end instead of ]", position);
+                                        process::exit(1);
+                                    },
+                                    ScopeTypes::Let(count) => {
+                                        tokens.push(OpCodes::Unbind(count));
+                                        bindings.truncate(bindings.len() - count);
+                                    },
+                                    ScopeTypes::Const(name) => {
+                                        scopes.remove(&start_scope.0);
+                                        let body = tokens.split_off(start_scope.0);
+                                        positions.truncate(start_scope.0);
+                                        match Interpreter::evaluate(&body) {
+                                            Ok(value) => {
+                                                consts.insert(name, value);
+                                            },
+                                            Err(message) => {
+                                                println!("{}: Error in constant {}: {}", position, name, message);
+                                                process::exit(1);
+                                            },
+                                        }
+                                    },
+                                    ScopeTypes::Try(_) => {
+                                        println!("{}: try without catch", position);
+                                        process::exit(1);
+                                    },
+                                    ScopeTypes::Catch(skip) => {
+                                        tokens[skip] = OpCodes::Goto(tokens.len());
+                                    },
+                                    ScopeTypes::While(_) => {
+                                        println!("{}: while without do", position);
+                                        process::exit(1);
+                                    },
+                                    ScopeTypes::Loop { condition, breaks } => {
+                                        tokens.push(OpCodes::Goto(condition));
+                                        scopes.insert(start_scope.0, tokens.len());
+                                        for exit in breaks {
+                                            tokens[exit] = OpCodes::Goto(tokens.len());
+                                        }
+                                    },
+                                    ScopeTypes::Match { cases, mut exits, default } => {
+                                        scopes.remove(&start_scope.0);
+                                        if !default {
+                                            if let Some(last) = cases.last() {
+                                                exits.push(tokens.len());
+                                                tokens.push(OpCodes::Goto(0));
+                                                Lexer::patch_case(&mut tokens, *last);
+                                            }
+                                            let covered = cases.iter().filter_map(|case| match &tokens[*case] {
+                                                OpCodes::Case(_, value, _) => Some(*value),
+                                                _ => None,
+                                            }).collect();
+                                            tokens.push(OpCodes::NoMatch(covered));
+                                        }
+                                        for exit in exits {
+                                            tokens[exit] = OpCodes::Goto(tokens.len());
+                                        }
+                                    },
                                     _ => {},
                                 }
                             },
@@ -276,6 +1267,30 @@ impl Lexer {
                             "(int)" => {
                                 tokens.push(OpCodes::Cast(Types::Int));
                             },
+                            "(uint)" => {
+                                tokens.push(OpCodes::Cast(Types::Uint));
+                            },
+                            "(i8)" => {
+                                tokens.push(OpCodes::Cast(Types::I8));
+                            },
+                            "(i16)" => {
+                                tokens.push(OpCodes::Cast(Types::I16));
+                            },
+                            "(i32)" => {
+                                tokens.push(OpCodes::Cast(Types::I32));
+                            },
+                            "(u8)" => {
+                                tokens.push(OpCodes::Cast(Types::U8));
+                            },
+                            "(u16)" => {
+                                tokens.push(OpCodes::Cast(Types::U16));
+                            },
+                            "(u32)" => {
+                                tokens.push(OpCodes::Cast(Types::U32));
+                            },
+                            "(float)" => {
+                                tokens.push(OpCodes::Cast(Types::Float));
+                            },
                             "(ptr)" => {
                                 tokens.push(OpCodes::Cast(Types::Pointer));
                             },
@@ -288,12 +1303,48 @@ impl Lexer {
                             "store" => {
                                 tokens.push(OpCodes::Store);
                             },
+                            "alloc" => {
+                                tokens.push(OpCodes::Alloc);
+                            },
+                            "free" => {
+                                tokens.push(OpCodes::Free);
+                            },
                             "use" => {
                                 flag = "use";
                             },
                             "proc" => {
                                 flag = "proc";
                             },
+                            "memory" => {
+                                if !scope.is_empty() {
+                                    println!("{}: memory can only be declared at the top level", position);
+                                    process::exit(1);
+                                }
+                                flag = "memory";
+                            },
+                            "struct" => {
+                                flag = "struct";
+                            },
+                            "enum" => {
+                                flag = "enum";
+                            },
+                            "const" => {
+                                flag = "const";
+                            },
+                            "macro" => {
+                                flag = "macro";
+                            },
+                            "local" => {
+                                if !scope.iter().any(|scope| matches!(scope.1, ScopeTypes::Proc)) {
+                                    println!("{}: local used outside of a procedure", position);
+                                    process::exit(1);
+                                }
+                                flag = "local";
+                            },
+                            "let" => {
+                                let_start = bindings.len();
+                                flag = "let";
+                            },
                             "dup" => {
                                 tokens.push(OpCodes::Dup);
                             },
@@ -315,15 +1366,73 @@ impl Lexer {
                             "read" => {
                                 tokens.push(OpCodes::Syscalls(Syscalls::Read));
                             },
+                            "call" => {
+                                tokens.push(OpCodes::Call);
+                            },
                             "open" => {
                                 tokens.push(OpCodes::Syscalls(Syscalls::Open));
                             },
                             _ => {
-                                let int_token = token.parse::<u64>();
-                                if int_token.is_ok() {
-                                    tokens.push(OpCodes::Push(Types::Int, int_token.unwrap(), String::new()));
+                                if let Some((number_type, value)) = Lexer::parse_number(&token) {
+                                    tokens.push(OpCodes::Push(number_type, value, String::new()));
                                 } else if token.ends_with(":") && token.len() > 1 {
-                                    labels.insert(token, tokens.len()-1);
+                                    if !scope.iter().any(|scope| matches!(scope.1, ScopeTypes::Proc)) {
+                                        println!("{}: Label {} declared outside of a procedure", position, token);
+                                        process::exit(1);
+                                    }
+                                    let label = Lexer::hygienic(token, &expansions);
+                                    if labels.contains_key(&label) {
+                                        println!("{}: Label {} is declared twice", position, label);
+                                        process::exit(1);
+                                    }
+                                    labels.insert(label, Label { target: tokens.len(), path: Lexer::block_path(&scope) });
+                                } else if let Some(index) = bindings.iter().rposition(|binding| *binding == token) {
+                                    tokens.push(OpCodes::Local(index));
+                                } else if let Some(offset) = buffers.get(&token) {
+                                    tokens.push(OpCodes::Buffer(*offset));
+                                } else if let Some((const_type, value)) = consts.get(&token) {
+                                    tokens.push(OpCodes::Push(const_type.clone(), *value, String::new()));
+                                } else if let Some((body, body_labels)) = macros.get(&token) {
+                                    if expansions.iter().any(|expansion| expansion.name == token) {
+                                        println!("{}: Recursive expansion of macro {}", position, token);
+                                        process::exit(1);
+                                    }
+                                    let text = format!("{}\n", body).into_bytes();
+                                    for expansion in expansions.iter_mut() {
+                                        expansion.end += text.len();
+                                    }
+                                    expansions.push(Expansion {
+                                        name: token.clone(),
+                                        end: index + text.len(),
+                                        id: expansion_count,
+                                        labels: body_labels.clone(),
+                                        position: position.clone(),
+                                    });
+                                    expansion_count += 1;
+                                    source.splice(index..index, text);
+                                } else if let Some(fields) = token.strip_prefix("sizeof(").and_then(|name| name.strip_suffix(')')).and_then(|name| structs.get(name)) {
+                                    tokens.push(OpCodes::Push(Types::Int, fields.len() as u64, String::new()));
+                                } else if let Some(cast_type) = token.strip_prefix('(').and_then(|name| name.strip_suffix(')')).and_then(|name| Lexer::parse_type(name, &structs, &enums)) {
+                                    if matches!(cast_type, Types::Procedure(..)) {
+                                        println!("{}: Can't cast to a procedure type, use &name to reference a procedure", position);
+                                        process::exit(1);
+                                    }
+                                    tokens.push(OpCodes::Cast(cast_type));
+                                } else if let Some((name, variant)) = token.split_once('.').filter(|(name, _)| enums.contains_key(*name)) {
+                                    let value = enums.get(name).unwrap().iter().position(|known| known == variant);
+                                    if value.is_none() {
+                                        println!("{}: Unknown variant: {}", position, token);
+                                        process::exit(1);
+                                    }
+                                    tokens.push(OpCodes::Push(Types::Enum(name.to_string()), value.unwrap() as u64, String::new()));
+                                } else if let Some((struct_type, offset, field_type, store)) = Lexer::field_access(&token, &structs) {
+                                    if store {
+                                        tokens.push(OpCodes::FieldStore(struct_type, offset, field_type));
+                                    } else {
+                                        tokens.push(OpCodes::FieldLoad(struct_type, offset, field_type));
+                                    }
+                                } else if let Some(name) = token.strip_prefix('&').filter(|name| !name.is_empty()) {
+                                    tokens.push(OpCodes::Reference(name.to_string()));
                                 } else {
                                     tokens.push(OpCodes::Ident(token));
                                 }
@@ -335,8 +1444,45 @@ impl Lexer {
                 _ => {token = token + &character},
             }
         }
+        if flag == "macro_body" {
+            println!("{}: Macro {} is never closed with end", position, macro_name);
+            process::exit(1);
+        }
+        if let Some((_, scope_type, opened)) = scope.last() {
+            let block = match scope_type {
+                ScopeTypes::Proc => format!("Procedure {}", procedures.1.last().unwrap().Proc),
+                ScopeTypes::Quotation { .. } => {
+                    println!("{}: Quotation is never closed with ]", opened);
+                    process::exit(1);
+                },
+                ScopeTypes::Const(name) => format!("Constant {}", name),
+                ScopeTypes::If => String::from("if"),
+                ScopeTypes::Let(_) => String::from("let"),
+                ScopeTypes::Match { .. } => String::from("match"),
+                ScopeTypes::While(_) | ScopeTypes::Loop { .. } => String::from("while"),
+                ScopeTypes::Try(_) => String::from("try"),
+                ScopeTypes::Catch(_) => String::from("catch"),
+            };
+            println!("{}: {} is never closed with end", opened, block);
+            process::exit(1);
+        }
+        while positions.len() < tokens.len() {
+            positions.push(position.clone());
+        }
         tokens.push(OpCodes::EOP);
-        return (tokens, scopes, procedures.0, labels);
+        positions.push(Position { file: file.to_string(), line, column });
+        Program {
+            tokens,
+            positions,
+            scopes,
+            procedures: procedures.0,
+            globals,
+            data_size,
+            structs,
+            enums,
+            consts,
+            macros,
+        }
     }
 }
 
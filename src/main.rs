@@ -9,19 +9,44 @@ use std::process;
 
 pub struct Args {
     debug: bool,
+    leak_check: bool,
+    max_stack: usize,
+    max_calls: usize,
+}
+
+/// Parses the number following an option like `--max-stack`
+fn parse_limit(argv: &[String], ctr: usize) -> usize {
+    match argv.get(ctr + 1).map(|limit| limit.parse::<usize>()) {
+        Some(Ok(limit)) if limit > 0 => limit,
+        _ => {
+            println!("Expected a positive number after {}", argv[ctr]);
+            process::exit(1);
+        },
+    }
 }
 
 fn main() {
     let (argv, argc) = (env::args().collect::<Vec<String>>(), env::args().count());
     if argc < 2 {
         println!(
-            "{}\n{}\n{}",
-            format!("{}Usage{}: put <File> [Options]",
+            "{}\n  Options:\n{}\n{}\n{}\n{}",
+            format_args!("{}Usage{}: put <File> [Options]",
                     termion::color::Fg(termion::color::Yellow),
                     termion::color::Fg(termion::color::Reset)
                    ),
-            format!("  Options:"),
-            format!("    {}-d{}: Debug mode",
+            format_args!("    {}-d{}: Debug mode",
+                    termion::color::Fg(termion::color::Green),
+                    termion::color::Fg(termion::color::Reset),
+                   ),
+            format_args!("    {}--leak-check{}: Report allocations that were never freed",
+                    termion::color::Fg(termion::color::Green),
+                    termion::color::Fg(termion::color::Reset),
+                   ),
+            format_args!("    {}--max-stack <N>{}: Maximum number of values on the stack (default 1000000)",
+                    termion::color::Fg(termion::color::Green),
+                    termion::color::Fg(termion::color::Reset),
+                   ),
+            format_args!("    {}--max-calls <N>{}: Maximum depth of nested procedure calls (default 10000)",
                     termion::color::Fg(termion::color::Green),
                     termion::color::Fg(termion::color::Reset),
                    ),
@@ -31,19 +56,33 @@ fn main() {
 
     let mut args = Args {
         debug: false,
+        leak_check: false,
+        max_stack: 1_000_000,
+        max_calls: 10_000,
     };
 
     let mut ctr = 2;
     while ctr < argv.len() {
         if argv[ctr] == "-d" {
             args.debug = true;
+        } else if argv[ctr] == "--leak-check" {
+            args.leak_check = true;
+        } else if argv[ctr] == "--max-stack" {
+            args.max_stack = parse_limit(&argv, ctr);
+            ctr += 1;
+        } else if argv[ctr] == "--max-calls" {
+            args.max_calls = parse_limit(&argv, ctr);
+            ctr += 1;
         }
         ctr += 1;
     }
 
-    let tokens = lexer::Lexer::tokenize(&argv[1]);
-    println!("{:?}", tokens);
-    interpreter::Interpreter::run(tokens, args);
+    let program = lexer::Lexer::tokenize(&argv[1]);
+    if args.debug {
+        println!("{:?}", program);
+    }
+    typechecker::TypeChecker::check(&program);
+    interpreter::Interpreter::run(program, args);
 }
 
 
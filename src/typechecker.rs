@@ -0,0 +1,394 @@
+use crate::lexer;
+
+use std::process;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct State {
+    stack: Vec<lexer::Types>,
+    locals: Vec<lexer::Types>,
+}
+
+pub struct TypeChecker;
+
+impl TypeChecker {
+    pub fn error(position: &lexer::Position, message: String) -> ! {
+        println!("{}: Type error: {}", position, message);
+        process::exit(1);
+    }
+
+    /// Whether `value` can be used where `expected` is wanted. A quotation can be used as a
+    /// procedure once its body is checked against the signature of the procedure
+    fn accepts(program: &lexer::Program, expected: &lexer::Types, value: &lexer::Types) -> bool {
+        if let (lexer::Types::Procedure(parameter_types, return_types), lexer::Types::Quotation(name)) = (expected, value) {
+            let quotation = program.procedures.get(name).unwrap();
+            TypeChecker::check_body(program, quotation, parameter_types.iter().rev().cloned().collect(), Some(return_types));
+            return true;
+        }
+        *expected == lexer::Types::Unknown || expected == value
+    }
+
+    /// Merges the types two paths bring to the same instruction, a quotation on one path
+    /// takes the procedure type of the other
+    fn merge(program: &lexer::Program, known: &[lexer::Types], other: &[lexer::Types]) -> Option<Vec<lexer::Types>> {
+        if known.len() != other.len() {
+            return None;
+        }
+        known.iter().zip(other).map(|(known, other)| match (known, other) {
+            (lexer::Types::Procedure(..), lexer::Types::Quotation(_)) => TypeChecker::accepts(program, known, other).then(|| known.clone()),
+            (lexer::Types::Quotation(_), lexer::Types::Procedure(..)) => TypeChecker::accepts(program, other, known).then(|| other.clone()),
+            _ => (known == other).then(|| known.clone()),
+        }).collect()
+    }
+
+    /// A quotation that is dropped, stored or cast is never called through a known signature,
+    /// so its body is checked as if it was called on an empty stack
+    fn discard(program: &lexer::Program, value: &lexer::Types) {
+        if let lexer::Types::Quotation(name) = value {
+            TypeChecker::check_body(program, program.procedures.get(name).unwrap(), Vec::new(), None);
+        }
+    }
+
+    /// Pops the expected types off the simulated stack, the first one being the top.
+    /// A quotation passed where a procedure is expected is checked against its signature
+    pub fn expect(program: &lexer::Program, position: &lexer::Position, state: &mut State, expected_types: &[lexer::Types]) -> Vec<lexer::Types> {
+        let mut values = Vec::new();
+        for expected in expected_types {
+            let value = state.stack.pop();
+            if value.is_none() {
+                TypeChecker::error(position, String::from("Not enough elements on the stack"));
+            }
+            let value = value.unwrap();
+            if !TypeChecker::accepts(program, expected, &value) {
+                TypeChecker::error(position, format!("Expected {:?} but got {:?}", expected, value));
+            }
+            if *expected == lexer::Types::Unknown {
+                values.push(value);
+            } else {
+                values.push(expected.clone());
+            }
+        }
+        values
+    }
+
+    pub fn check(program: &lexer::Program) {
+        let mut procedures = program.procedures.values().collect::<Vec<&lexer::Procedure>>();
+        procedures.sort_by_key(|procedure| procedure.Location);
+        // Quotations are checked where they are used or discarded, since their stack effect is inferred
+        for procedure in procedures.into_iter().filter(|procedure| !procedure.is_quotation()) {
+            TypeChecker::check_body(
+                program,
+                procedure,
+                procedure.ParameterTypes.iter().rev().cloned().collect(),
+                Some(&procedure.ReturnTypes),
+            );
+        }
+    }
+
+    /// Merges the state `ip` leaves for `next` into what is known about `next` and returns
+    /// whether that changed, so `next` has to be walked (again)
+    fn arrive(program: &lexer::Program, states: &mut HashMap<usize, State>, ip: usize, next: usize, mut state: State) -> bool {
+        if let Some(known) = states.get(&next) {
+            let stack = TypeChecker::merge(program, &known.stack, &state.stack);
+            if stack.is_none() && matches!(program.tokens[ip], lexer::OpCodes::NoMatch(_)) {
+                TypeChecker::error(
+                    &program.positions[ip],
+                    format!("The cases of the match leave {:?} but {:?} is left when no case matches, add a default", known.stack, state.stack),
+                );
+            } else if stack.is_none() {
+                TypeChecker::error(
+                    &program.positions[next],
+                    format!("Inconsistent stack, {:?} on one path but {:?} on another", known.stack, state.stack),
+                );
+            }
+            let locals = TypeChecker::merge(program, &known.locals, &state.locals);
+            if locals.is_none() {
+                TypeChecker::error(
+                    &program.positions[next],
+                    format!("Inconsistent let bindings, {:?} on one path but {:?} on another", known.locals, state.locals),
+                );
+            }
+            state = State { stack: stack.unwrap(), locals: locals.unwrap() };
+            if state == *known {
+                return false;
+            }
+        }
+        states.insert(next, state);
+        true
+    }
+
+    /// Walks every reachable instruction of a procedure, requiring all paths
+    /// that meet at an instruction to agree on the stack and the locals.
+    /// The path of a match without default where no case matched is walked last, so
+    /// cases that leave a different stack are reported at the end of the match.
+    /// Without declared return types the stack left at the returns is inferred and returned
+    fn check_body(program: &lexer::Program, procedure: &lexer::Procedure, stack: Vec<lexer::Types>, returns: Option<&[lexer::Types]>) -> Vec<lexer::Types> {
+        let mut states: HashMap<usize, State> = HashMap::new();
+        states.insert(
+            procedure.Location,
+            State {
+                stack,
+                locals: Vec::new(),
+            }
+        );
+        let mut pending = vec![procedure.Location];
+        let mut unmatched = Vec::new();
+
+        loop {
+            if let Some(ip) = pending.pop() {
+                let state = states.get(&ip).unwrap().clone();
+                for (next, state) in TypeChecker::step(program, procedure, returns, ip, state) {
+                    if matches!(program.tokens[ip], lexer::OpCodes::NoMatch(_)) {
+                        unmatched.push((ip, next, state));
+                    } else if TypeChecker::arrive(program, &mut states, ip, next, state) {
+                        pending.push(next);
+                    }
+                }
+            } else if let Some((ip, next, state)) = unmatched.pop() {
+                if TypeChecker::arrive(program, &mut states, ip, next, state) {
+                    pending.push(next);
+                }
+            } else {
+                break;
+            }
+        }
+
+        let mut returned: Option<&State> = None;
+        let mut ips = states.keys().filter(|ip| matches!(program.tokens[**ip], lexer::OpCodes::Return)).collect::<Vec<&usize>>();
+        ips.sort();
+        for ip in ips {
+            let state = states.get(ip).unwrap();
+            if returned.is_some_and(|returned| returned.stack != state.stack) {
+                TypeChecker::error(
+                    &program.positions[*ip],
+                    format!("Inconsistent stack, returns with {:?} here but {:?} before", state.stack, returned.unwrap().stack),
+                );
+            }
+            returned = Some(state);
+        }
+        returned.map(|returned| returned.stack.clone()).unwrap_or_default()
+    }
+
+    /// Returns the instructions that can follow `ip` together with the state they start in
+    fn step(program: &lexer::Program, procedure: &lexer::Procedure, returns: Option<&[lexer::Types]>, ip: usize, mut state: State) -> Vec<(usize, State)> {
+        let position = &program.positions[ip];
+        match &program.tokens[ip] {
+            lexer::OpCodes::Push(push_type, _, _) => {
+                match push_type {
+                    lexer::Types::String => state.stack.push(lexer::Types::Pointer),
+                    _ => state.stack.push(push_type.clone()),
+                }
+            },
+            lexer::OpCodes::Arithmetic(operator) => {
+                let values = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown, lexer::Types::Unknown]);
+                let result_type = operator.result_type(&values[1], &values[0]);
+                if result_type.is_none() {
+                    TypeChecker::error(position, format!("Can't apply {:?} to {:?} and {:?}", operator, values[1], values[0]));
+                }
+                state.stack.push(result_type.unwrap());
+            },
+            lexer::OpCodes::DivMod => {
+                let values = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown, lexer::Types::Unknown]);
+                let result_type = lexer::Arithmetic::Div.result_type(&values[1], &values[0]);
+                if result_type.is_none() {
+                    TypeChecker::error(position, format!("Can't apply divmod to {:?} and {:?}", values[1], values[0]));
+                }
+                state.stack.push(result_type.clone().unwrap());
+                state.stack.push(result_type.unwrap());
+            },
+            lexer::OpCodes::Bitwise(operator) => {
+                let mut values = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown]);
+                if !matches!(operator, lexer::Bitwise::Not) {
+                    values.extend(TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown]));
+                }
+                let result_type = operator.result_type(values.last().unwrap(), &values[0]);
+                if result_type.is_none() {
+                    TypeChecker::error(position, format!("Can't apply {:?} to {:?}", operator, values));
+                }
+                state.stack.push(result_type.unwrap());
+            },
+            lexer::OpCodes::Equality(operator) => {
+                let values = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown, lexer::Types::Unknown]);
+                if !operator.accepts(&values[1], &values[0]) {
+                    TypeChecker::error(position, format!("Can't compare {:?} and {:?}", values[1], values[0]));
+                }
+                state.stack.push(lexer::Types::Bool);
+            },
+            lexer::OpCodes::Logic(operator) => {
+                match operator {
+                    lexer::Logic::Not => TypeChecker::expect(program, position, &mut state, &[lexer::Types::Bool]),
+                    _ => TypeChecker::expect(program, position, &mut state, &[lexer::Types::Bool, lexer::Types::Bool]),
+                };
+                state.stack.push(lexer::Types::Bool);
+            },
+            lexer::OpCodes::Cast(cast_type) => {
+                let value = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown]);
+                TypeChecker::discard(program, &value[0]);
+                state.stack.push(cast_type.clone());
+            },
+            lexer::OpCodes::Ident(ident) => {
+                if let Some(callee) = program.procedures.get(ident) {
+                    TypeChecker::expect(program, position, &mut state, &callee.ParameterTypes);
+                    state.stack.extend(callee.ReturnTypes.iter().rev().cloned());
+                } else if program.globals.contains_key(ident) {
+                    state.stack.push(lexer::Types::Pointer);
+                } else {
+                    TypeChecker::error(position, format!("Unknown ident: {}", ident));
+                }
+            },
+            lexer::OpCodes::Reference(name) => {
+                let callee = program.procedures.get(name);
+                if callee.is_none() {
+                    TypeChecker::error(position, format!("Unknown procedure: {}", name));
+                }
+                let callee = callee.unwrap();
+                if callee.is_quotation() {
+                    state.stack.push(lexer::Types::Quotation(name.clone()));
+                } else {
+                    state.stack.push(lexer::Types::Procedure(callee.ParameterTypes.clone(), callee.ReturnTypes.clone()));
+                }
+            },
+            lexer::OpCodes::Call => {
+                let reference = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown]);
+                if let lexer::Types::Procedure(parameter_types, return_types) = &reference[0] {
+                    TypeChecker::expect(program, position, &mut state, parameter_types);
+                    state.stack.extend(return_types.iter().rev().cloned());
+                } else if let lexer::Types::Quotation(name) = &reference[0] {
+                    let quotation = program.procedures.get(name).unwrap();
+                    state.stack = TypeChecker::check_body(program, quotation, state.stack, None);
+                } else {
+                    TypeChecker::error(position, format!("Can't call {:?}", reference[0]));
+                }
+            },
+            lexer::OpCodes::Bind(count) => {
+                let mut values = TypeChecker::expect(program, position, &mut state, &vec![lexer::Types::Unknown; *count]);
+                values.reverse();
+                state.locals.extend(values);
+            },
+            lexer::OpCodes::Unbind(count) => {
+                if state.locals.len() < *count {
+                    TypeChecker::error(position, format!("Ending a let block of {} binding(s) but only {} are bound", count, state.locals.len()));
+                }
+                state.locals.truncate(state.locals.len() - count);
+            },
+            lexer::OpCodes::Local(index) => {
+                let local = state.locals.get(*index);
+                if local.is_none() {
+                    TypeChecker::error(position, format!("Let binding {} is not bound on this path", index));
+                }
+                state.stack.push(local.unwrap().clone());
+            },
+            lexer::OpCodes::Buffer(_) => {
+                state.stack.push(lexer::Types::Pointer);
+            },
+            lexer::OpCodes::Goto(target) => {
+                return vec![(*target, state)];
+            },
+            lexer::OpCodes::Case(case_type, _, next) => {
+                let unmatched = state.clone();
+                TypeChecker::expect(program, position, &mut state, std::slice::from_ref(case_type));
+                return vec![(ip + 1, state), (*next, unmatched)];
+            },
+            lexer::OpCodes::NoMatch(covered) => {
+                let value = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown]);
+                if let lexer::Types::Enum(name) = &value[0] {
+                    let variants = program.enums.get(name).unwrap();
+                    let missing = (0..variants.len()).filter(|variant| !covered.contains(&(*variant as u64))).map(|variant| variants[variant].as_str()).collect::<Vec<&str>>();
+                    if missing.is_empty() {
+                        return vec![];
+                    }
+                    TypeChecker::error(position, format!("Match on {} doesn't cover {}", name, missing.join(", ")));
+                }
+            },
+            lexer::OpCodes::Try(catch) => {
+                let mut thrown = state.clone();
+                thrown.stack.push(lexer::Types::Pointer);
+                return vec![(ip + 1, state), (*catch, thrown)];
+            },
+            lexer::OpCodes::EndTry => {},
+            lexer::OpCodes::Throw => {
+                TypeChecker::expect(program, position, &mut state, &[lexer::Types::Pointer]);
+                return vec![];
+            },
+            lexer::OpCodes::Syscalls(syscall) => {
+                match syscall {
+                    lexer::Syscalls::Open => {},
+                    lexer::Syscalls::Read => {
+                        TypeChecker::expect(program, position, &mut state, &[lexer::Types::Int, lexer::Types::Int]);
+                        state.stack.push(lexer::Types::Pointer);
+                    },
+                    lexer::Syscalls::Write => {
+                        TypeChecker::expect(program, position, &mut state, &[lexer::Types::Int, lexer::Types::Pointer, lexer::Types::Int]);
+                    },
+                }
+            },
+            lexer::OpCodes::Return => {
+                if returns.is_none() {
+                    return vec![];
+                }
+                let expected = returns.unwrap().iter().rev().cloned().collect::<Vec<lexer::Types>>();
+                let accepted = state.stack.len() == expected.len()
+                    && expected.iter().zip(&state.stack).all(|(expected, value)| TypeChecker::accepts(program, expected, value));
+                if !accepted {
+                    TypeChecker::error(
+                        position,
+                        format!("Procedure {} should leave {:?} on the stack but leaves {:?}", procedure.Proc, expected, state.stack),
+                    );
+                }
+                return vec![];
+            },
+            lexer::OpCodes::Load => {
+                TypeChecker::expect(program, position, &mut state, &[lexer::Types::Pointer]);
+                state.stack.push(lexer::Types::Int);
+            },
+            lexer::OpCodes::Store => {
+                let values = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Pointer, lexer::Types::Unknown]);
+                TypeChecker::discard(program, &values[1]);
+            },
+            lexer::OpCodes::FieldLoad(struct_type, _, field_type) => {
+                TypeChecker::expect(program, position, &mut state, std::slice::from_ref(struct_type));
+                state.stack.push(field_type.clone());
+            },
+            lexer::OpCodes::FieldStore(struct_type, _, field_type) => {
+                TypeChecker::expect(program, position, &mut state, &[struct_type.clone(), field_type.clone()]);
+            },
+            lexer::OpCodes::Alloc => {
+                TypeChecker::expect(program, position, &mut state, &[lexer::Types::Int]);
+                state.stack.push(lexer::Types::Pointer);
+            },
+            lexer::OpCodes::Free => {
+                TypeChecker::expect(program, position, &mut state, &[lexer::Types::Pointer]);
+            },
+            lexer::OpCodes::Dup => {
+                let value = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown]);
+                state.stack.push(value[0].clone());
+                state.stack.push(value[0].clone());
+            },
+            lexer::OpCodes::Swap => {
+                let values = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown, lexer::Types::Unknown]);
+                state.stack.push(values[0].clone());
+                state.stack.push(values[1].clone());
+            },
+            lexer::OpCodes::Rot => {
+                let values = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown, lexer::Types::Unknown, lexer::Types::Unknown]);
+                state.stack.extend(values);
+            },
+            lexer::OpCodes::Drop | lexer::OpCodes::Print => {
+                let value = TypeChecker::expect(program, position, &mut state, &[lexer::Types::Unknown]);
+                TypeChecker::discard(program, &value[0]);
+            },
+            lexer::OpCodes::If => {
+                TypeChecker::expect(program, position, &mut state, &[lexer::Types::Bool]);
+                let end = program.scopes.get(&(ip+1));
+                if end.is_none() {
+                    TypeChecker::error(position, String::from("Branch without a matching end"));
+                }
+                return vec![(ip + 1, state.clone()), (*end.unwrap(), state)];
+            },
+            lexer::OpCodes::EOP => {
+                return vec![];
+            },
+        }
+        vec![(ip + 1, state)]
+    }
+}
@@ -0,0 +1,53 @@
+//! Runs every program in tests/programs and compares what it prints with the `.out` file next
+//! to it. Programs in `pass` have to run successfully and programs in `fail` have to be
+//! rejected. A `.args` file holds extra options and a `.in` file what is written to stdin.
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+fn run(dir: &str, succeeds: bool) {
+    let mut programs = fs::read_dir(Path::new("tests/programs").join(dir))
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "put"))
+        .collect::<Vec<_>>();
+    programs.sort();
+    assert!(!programs.is_empty(), "no programs in tests/programs/{}", dir);
+
+    let mut failures = Vec::new();
+    for program in programs {
+        let args = fs::read_to_string(program.with_extension("args")).unwrap_or_default();
+        let input = fs::read_to_string(program.with_extension("in")).unwrap_or_default();
+        let expected = fs::read_to_string(program.with_extension("out")).unwrap();
+
+        let mut child = Command::new(env!("CARGO_BIN_EXE_tup"))
+            .arg(&program)
+            .args(args.split_whitespace())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child.stdin.take().unwrap().write_all(input.as_bytes()).unwrap();
+        let output = child.wait_with_output().unwrap();
+        let stdout = String::from_utf8_lossy(&output.stdout);
+
+        if output.status.success() != succeeds {
+            failures.push(format!("{}: exited with {}\n{}", program.display(), output.status, stdout));
+        } else if stdout != expected {
+            failures.push(format!("{}: expected\n{}but got\n{}", program.display(), expected, stdout));
+        }
+    }
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+#[test]
+fn accepted_programs() {
+    run("pass", true);
+}
+
+#[test]
+fn rejected_programs() {
+    run("fail", false);
+}